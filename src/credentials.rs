@@ -1,13 +1,37 @@
+use crate::secrets::{self, SecretStore};
 use crate::utils;
 use anyhow::{self, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
+use std::str::FromStr;
+
+/// Prefixes of the tokens made by [`Credentials::to_token`].
+const TOKEN_PREFIX: &str = "qf-creds:";
+const PROTECTED_TOKEN_PREFIX: &str = "qf-creds-protected:";
+
+/// Prefix of the environment variables read by [`Credentials::from_env`].
+pub const ENV_PREFIX: &str = "QUICK_FLASH_STORAGE_";
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Hash)]
 pub enum StorageType {
     R2,
+    /// Any S3-compatible store reached through `storage_endpoint`
+    S3,
+}
+
+impl FromStr for StorageType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "r2" => Ok(StorageType::R2),
+            "s3" => Ok(StorageType::S3),
+            _ => anyhow::bail!("Invalid storage type \"{}\", expected r2 or s3", s),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Hash)]
@@ -15,10 +39,23 @@ pub struct Credentials {
     pub user_storage_name: String,
     pub storage_type: StorageType,
     pub storage_name: String,
+    /// Only used by R2 storages
+    #[serde(default)]
     pub storage_account_id: String,
+    /// Endpoint URL of S3 storages, e.g. `https://minio.example.com:9000`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_endpoint: Option<String>,
+    /// Region of S3 storages, defaults to `us-east-1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_region: Option<String>,
     pub storage_access_key: String,
+    /// Only saved in the credentials file for [`SecretStore::Plaintext`]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub storage_secret_key: String,
+    #[serde(default)]
     pub timestamp: i64,
+    #[serde(default)]
+    pub secret_store: SecretStore,
 }
 
 impl Credentials {
@@ -34,12 +71,64 @@ impl Credentials {
             storage_type: StorageType::R2,
             storage_name,
             storage_account_id,
+            storage_endpoint: None,
+            storage_region: None,
             storage_access_key,
             storage_secret_key,
             timestamp: Utc::now().timestamp(),
+            secret_store: SecretStore::Plaintext,
         }
     }
 
+    /// Builds credentials from the `QUICK_FLASH_STORAGE_*` environment
+    /// variables, returns `None` when none of them are set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        Self::from_vars(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+    }
+
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<Option<Self>> {
+        let var = |suffix: &str| lookup(&format!("{}{}", ENV_PREFIX, suffix));
+        let required =
+            |suffix: &str| var(suffix).context(format!("{}{} is not set", ENV_PREFIX, suffix));
+        let fields = [
+            "TYPE",
+            "NAME",
+            "BUCKET",
+            "ACCOUNT_ID",
+            "ENDPOINT",
+            "REGION",
+            "ACCESS_KEY",
+            "SECRET_KEY",
+        ];
+        if fields.iter().all(|f| var(f).is_none()) {
+            return Ok(None);
+        }
+
+        let storage_type = match var("TYPE") {
+            Some(storage_type) => storage_type.parse()?,
+            None => StorageType::R2,
+        };
+        let storage_name = required("BUCKET")?;
+        Ok(Some(Credentials {
+            user_storage_name: var("NAME").unwrap_or_else(|| storage_name.clone()),
+            storage_account_id: match storage_type {
+                StorageType::R2 => required("ACCOUNT_ID")?,
+                StorageType::S3 => String::new(),
+            },
+            storage_endpoint: match storage_type {
+                StorageType::R2 => None,
+                StorageType::S3 => Some(required("ENDPOINT")?),
+            },
+            storage_region: var("REGION"),
+            storage_access_key: required("ACCESS_KEY")?,
+            storage_secret_key: required("SECRET_KEY")?,
+            storage_type,
+            storage_name,
+            timestamp: Utc::now().timestamp(),
+            secret_store: SecretStore::Plaintext,
+        }))
+    }
+
     pub fn read_from_path(path: &Path) -> anyhow::Result<Self> {
         let contents = fs::read_to_string(path).context(format!(
             "Failed to read credentials file {}",
@@ -54,27 +143,120 @@ impl Credentials {
 
     pub fn write_to_path(&self, path: &Path) -> anyhow::Result<()> {
         let contents = toml::to_string(self)?;
-        fs::write(path, contents)?;
+        utils::write_private(path, contents.as_bytes())?;
         Ok(())
     }
+
+    /// Encodes the credentials into a single line token for sharing them,
+    /// the token is encrypted when a `passphrase` is given.
+    pub fn to_token(&self, passphrase: Option<&str>) -> anyhow::Result<String> {
+        let json = serde_json::to_string(&Credentials {
+            secret_store: SecretStore::Plaintext,
+            ..self.clone()
+        })?;
+        Ok(match passphrase {
+            Some(passphrase) => format!(
+                "{}{}",
+                PROTECTED_TOKEN_PREFIX,
+                BASE64.encode(secrets::encrypt(&json, passphrase)?)
+            ),
+            None => format!("{}{}", TOKEN_PREFIX, BASE64.encode(json)),
+        })
+    }
+
+    /// Whether the token needs a passphrase to be imported.
+    pub fn is_protected_token(token: &str) -> bool {
+        token.trim().starts_with(PROTECTED_TOKEN_PREFIX)
+    }
+
+    /// Decodes a token made by [`Credentials::to_token`], the imported
+    /// credentials keep their secret key in plaintext until they are saved.
+    pub fn from_token(token: &str, passphrase: Option<&str>) -> anyhow::Result<Self> {
+        let token = token.trim();
+        let decode = |data: &str| BASE64.decode(data).context("Token is not valid base64");
+        let json = if let Some(data) = token.strip_prefix(PROTECTED_TOKEN_PREFIX) {
+            let passphrase = passphrase.context("Token is protected by a passphrase")?;
+            secrets::decrypt(&decode(data)?, passphrase)?
+        } else if let Some(data) = token.strip_prefix(TOKEN_PREFIX) {
+            String::from_utf8(decode(data)?).context("Token is not valid text")?
+        } else {
+            anyhow::bail!(
+                "Not a credentials token, it should start with \"{}\"",
+                TOKEN_PREFIX
+            );
+        };
+        let mut creds: Credentials =
+            serde_json::from_str(&json).context("Failed to parse credentials token")?;
+        creds.timestamp = Utc::now().timestamp();
+        creds.secret_store = SecretStore::Plaintext;
+        Ok(creds)
+    }
 }
 
-pub fn get_credentials_from_command_line() -> anyhow::Result<Credentials> {
-    eprintln!("Input credentials for the R2 bucket below:");
-
-    eprint!("Bucket Name: ");
-    let storage_name = utils::read_line()?;
-    eprint!("Bucket Account ID: ");
-    let storage_account_id = utils::read_line()?;
-    eprint!("Bucket Access Key: ");
-    let storage_access_key = utils::read_line()?;
-    eprint!("Bucket Secret Key: ");
-    let storage_secret_key = utils::read_line()?;
-    eprint!(
-        "Optionally, name the storage for future reference [{}]: ",
-        &storage_name
-    );
-    let user_storage_name = utils::read_line().unwrap_or(storage_name.clone());
+/// Credential fields known up front, e.g. given as command line options.
+#[derive(Default, Clone, Debug)]
+pub struct PartialCredentials {
+    pub user_storage_name: Option<String>,
+    pub storage_name: Option<String>,
+    pub storage_account_id: Option<String>,
+    pub storage_access_key: Option<String>,
+    pub storage_secret_key: Option<String>,
+}
+
+impl PartialCredentials {
+    fn is_complete(&self) -> bool {
+        self.storage_name.is_some()
+            && self.storage_account_id.is_some()
+            && self.storage_access_key.is_some()
+            && self.storage_secret_key.is_some()
+    }
+}
+
+/// Hides all but the last four characters of a secret, shorter secrets are hidden completely.
+pub fn mask_secret(secret: &str) -> String {
+    let length = secret.chars().count();
+    match length {
+        0..12 => "*".repeat(length.max(4)),
+        _ => secret
+            .chars()
+            .enumerate()
+            .map(|(i, c)| if i < length - 4 { '*' } else { c })
+            .collect(),
+    }
+}
+
+/// Asks for the credential fields missing in `known`, the storage is named
+/// after the bucket unless a name is given or entered.
+pub fn get_credentials_from_command_line(known: PartialCredentials) -> anyhow::Result<Credentials> {
+    let prompt = |field: Option<String>, label: &str| -> anyhow::Result<String> {
+        match field {
+            Some(value) => Ok(value),
+            None => {
+                eprint!("{}: ", label);
+                Ok(utils::read_line()?)
+            }
+        }
+    };
+
+    let complete = known.is_complete();
+    if !complete {
+        eprintln!("Input credentials for the R2 bucket below:");
+    }
+    let storage_name = prompt(known.storage_name, "Bucket Name")?;
+    let storage_account_id = prompt(known.storage_account_id, "Bucket Account ID")?;
+    let storage_access_key = prompt(known.storage_access_key, "Bucket Access Key")?;
+    let storage_secret_key = prompt(known.storage_secret_key, "Bucket Secret Key")?;
+    let user_storage_name = match known.user_storage_name {
+        Some(name) => name,
+        None if complete => storage_name.clone(),
+        None => {
+            eprint!(
+                "Optionally, name the storage for future reference [{}]: ",
+                &storage_name
+            );
+            utils::read_line().unwrap_or(storage_name.clone())
+        }
+    };
 
     let creds = Credentials::new_r2(
         user_storage_name,
@@ -87,3 +269,111 @@ pub fn get_credentials_from_command_line() -> anyhow::Result<Credentials> {
     write_credentials(&path, &creds)?; */
     Ok(creds)
 }
+
+/// Asks the user to pick one of the saved storages.
+pub fn pick_storage_from_command_line(all_creds: &[Credentials]) -> anyhow::Result<&Credentials> {
+    eprintln!("Multiple storages found, select one:");
+    for (i, creds) in all_creds.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, creds.user_storage_name);
+    }
+    loop {
+        eprint!("Storage number [1-{}]: ", all_creds.len());
+        let line = utils::read_line().context("Failed to read storage selection")?;
+        match line.parse::<usize>() {
+            Ok(i) if (1..=all_creds.len()).contains(&i) => return Ok(&all_creds[i - 1]),
+            _ => eprintln!("Invalid storage number \"{}\"", line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_secret() {
+        assert_eq!(mask_secret(""), "****");
+        assert_eq!(mask_secret("abc"), "****");
+        assert_eq!(mask_secret("0123456789ab"), "********89ab");
+        assert_eq!(
+            mask_secret("a1b2c3d4e5f6a7b8c9d0e1f2"),
+            "********************e1f2"
+        );
+    }
+
+    #[test]
+    fn test_credentials_from_env() {
+        let vars = |pairs: &[(&str, &str)]| {
+            let pairs = pairs
+                .iter()
+                .map(|(k, v)| (format!("{}{}", ENV_PREFIX, k), v.to_string()))
+                .collect::<std::collections::HashMap<_, _>>();
+            Credentials::from_vars(|name| pairs.get(name).cloned())
+        };
+
+        assert_eq!(vars(&[]).unwrap(), None);
+        let creds = vars(&[
+            ("BUCKET", "firmware"),
+            ("ACCOUNT_ID", "account_id"),
+            ("ACCESS_KEY", "access_key"),
+            ("SECRET_KEY", "secret_key"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(creds.storage_type, StorageType::R2);
+        assert_eq!(creds.user_storage_name, "firmware");
+
+        let creds = vars(&[
+            ("TYPE", "S3"),
+            ("NAME", "minio"),
+            ("BUCKET", "firmware"),
+            ("ENDPOINT", "http://localhost:9000"),
+            ("ACCESS_KEY", "access_key"),
+            ("SECRET_KEY", "secret_key"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(creds.storage_type, StorageType::S3);
+        assert_eq!(creds.user_storage_name, "minio");
+        assert_eq!(
+            creds.storage_endpoint.as_deref(),
+            Some("http://localhost:9000")
+        );
+
+        assert_eq!(
+            vars(&[("TYPE", "s3"), ("BUCKET", "firmware")])
+                .unwrap_err()
+                .to_string(),
+            "QUICK_FLASH_STORAGE_ENDPOINT is not set"
+        );
+        assert!(vars(&[("TYPE", "gcs")]).is_err());
+    }
+
+    #[test]
+    fn test_tokens() {
+        let creds = Credentials::new_r2(
+            "test".to_string(),
+            "storage_name".to_string(),
+            "account_id".to_string(),
+            "access_key".to_string(),
+            "secret_key".to_string(),
+        );
+
+        let token = creds.to_token(None).unwrap();
+        assert!(!Credentials::is_protected_token(&token));
+        let imported = Credentials::from_token(&format!(" {}\n", token), None).unwrap();
+        assert_eq!(imported.storage_secret_key, "secret_key");
+        assert_eq!(imported.user_storage_name, "test");
+
+        let token = creds.to_token(Some("passphrase")).unwrap();
+        assert!(Credentials::is_protected_token(&token));
+        assert!(!token.contains(&BASE64.encode("secret_key")));
+        assert!(Credentials::from_token(&token, None).is_err());
+        assert!(Credentials::from_token(&token, Some("wrong")).is_err());
+        let imported = Credentials::from_token(&token, Some("passphrase")).unwrap();
+        assert_eq!(imported.storage_access_key, "access_key");
+
+        assert!(Credentials::from_token("secret_key", None).is_err());
+        assert!(Credentials::from_token("qf-creds:!!", None).is_err());
+    }
+}
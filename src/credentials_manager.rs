@@ -1,21 +1,61 @@
 use crate::credentials::Credentials;
+use crate::secrets::{self, SecretStore, SECRET_EXTENSION};
+use crate::utils;
 use anyhow::{self, Context};
 use chrono::Utc;
 use std::{
+    cell::OnceCell,
     hash::{DefaultHasher, Hash, Hasher},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 pub struct CredentialsManager {
     base_path: PathBuf,
+    secret_store: SecretStore,
+    passphrase: OnceCell<String>,
+}
+
+fn is_credentials_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "toml")
+}
+
+/// Identifies the secret key of the credentials saved at `path` in its store.
+fn secret_id(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
 }
 
 impl CredentialsManager {
     pub fn new(base_path: PathBuf) -> Self {
-        CredentialsManager { base_path }
+        CredentialsManager {
+            base_path,
+            secret_store: SecretStore::Plaintext,
+            passphrase: OnceCell::new(),
+        }
     }
 
-    pub fn get_all(&self) -> anyhow::Result<Vec<Credentials>> {
+    /// Sets where the secret keys of added credentials are kept.
+    pub fn with_secret_store(mut self, secret_store: SecretStore) -> Self {
+        self.secret_store = secret_store;
+        self
+    }
+
+    /// Sets the passphrase of the encrypted store instead of asking for it.
+    pub fn with_passphrase(self, passphrase: String) -> Self {
+        let _ = self.passphrase.set(passphrase);
+        self
+    }
+
+    fn passphrase(&self, new: bool) -> anyhow::Result<&str> {
+        if let Some(passphrase) = self.passphrase.get() {
+            return Ok(passphrase);
+        }
+        let passphrase = secrets::read_passphrase("Passphrase of the secret store: ", new)?;
+        Ok(self.passphrase.get_or_init(|| passphrase))
+    }
+
+    fn entries(&self) -> anyhow::Result<Vec<(PathBuf, Credentials)>> {
         if !self.base_path.exists() {
             return Ok(vec![]);
         }
@@ -23,29 +63,178 @@ impl CredentialsManager {
         self.base_path
             .read_dir()
             .context("Failed to read from credentials directory")?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |e| is_credentials_file(&e.path()))
+            })
             .map(|entry| {
                 let path = entry?.path();
-                Credentials::read_from_path(&path)
+                let creds = Credentials::read_from_path(&path)?;
+                Ok((path, creds))
             })
             .collect()
     }
 
-    pub fn remove(&self, user_storage_name: &str) -> anyhow::Result<()> {
-        self.base_path
+    /// Like [`CredentialsManager::get_all`], but without reading the secret
+    /// keys from their store, so they are empty unless kept in plaintext.
+    pub fn get_all_stored(&self) -> anyhow::Result<Vec<Credentials>> {
+        Ok(self.entries()?.into_iter().map(|(_, c)| c).collect())
+    }
+
+    pub fn get_all(&self) -> anyhow::Result<Vec<Credentials>> {
+        self.entries()?
+            .into_iter()
+            .map(|(path, mut creds)| {
+                creds.storage_secret_key = self.read_secret(&path, &creds)?;
+                Ok(creds)
+            })
+            .collect()
+    }
+
+    /// Loads the credentials of one storage including its secret key.
+    pub fn get(&self, user_storage_name: &str) -> anyhow::Result<Credentials> {
+        let path = self.find_path(user_storage_name)?;
+        let mut creds = Credentials::read_from_path(&path)?;
+        creds.storage_secret_key = self.read_secret(&path, &creds)?;
+        Ok(creds)
+    }
+
+    fn find_path(&self, user_storage_name: &str) -> anyhow::Result<PathBuf> {
+        Ok(self
+            .base_path
             .read_dir()
             .context("Failed to read from credentials directory")?
             .find(|entry| {
                 let path = entry.as_ref().map_or_else(|_| PathBuf::new(), |e| e.path());
-                Credentials::read_from_path(&path)
-                    .ok()
-                    .map_or(false, |c| c.user_storage_name == user_storage_name)
+                is_credentials_file(&path)
+                    && Credentials::read_from_path(&path)
+                        .ok()
+                        .is_some_and(|c| c.user_storage_name == user_storage_name)
             })
-            .context("Credentials not found")?
-            .and_then(|path| std::fs::remove_file(path.path()))
-            .context("Failed to remove credentials file")?;
+            .context("Credentials not found")??
+            .path())
+    }
+
+    fn read_secret(&self, path: &Path, creds: &Credentials) -> anyhow::Result<String> {
+        match creds.secret_store {
+            SecretStore::Plaintext => Ok(creds.storage_secret_key.clone()),
+            SecretStore::Keyring => secrets::keyring_entry(&secret_id(path))?
+                .get_password()
+                .context(format!(
+                    "Failed to read the secret key of \"{}\" from the keyring",
+                    creds.user_storage_name
+                )),
+            SecretStore::Encrypted => {
+                let data =
+                    std::fs::read(path.with_extension(SECRET_EXTENSION)).context(format!(
+                        "Failed to read the encrypted secret key of \"{}\"",
+                        creds.user_storage_name
+                    ))?;
+                secrets::decrypt(&data, self.passphrase(false)?)
+            }
+        }
+    }
+
+    fn write_secret(&self, path: &Path, store: SecretStore, secret: &str) -> anyhow::Result<()> {
+        match store {
+            SecretStore::Plaintext => Ok(()),
+            SecretStore::Keyring => secrets::keyring_entry(&secret_id(path))?
+                .set_password(secret)
+                .context("Failed to save the secret key to the keyring"),
+            SecretStore::Encrypted => {
+                /* all secrets share one passphrase, check it against an existing one */
+                let other = self
+                    .entries()?
+                    .into_iter()
+                    .find(|(p, c)| p != path && c.secret_store == SecretStore::Encrypted);
+                let passphrase = self.passphrase(other.is_none())?;
+                if let Some((other_path, other_creds)) = other {
+                    self.read_secret(&other_path, &other_creds)
+                        .context("The passphrase does not match the existing encrypted secrets")?;
+                }
+                utils::write_private(
+                    &path.with_extension(SECRET_EXTENSION),
+                    &secrets::encrypt(secret, passphrase)?,
+                )
+                .context("Failed to save the encrypted secret key")
+            }
+        }
+    }
+
+    fn delete_secret(&self, path: &Path, store: SecretStore) -> anyhow::Result<()> {
+        match store {
+            SecretStore::Plaintext => Ok(()),
+            SecretStore::Keyring => {
+                match secrets::keyring_entry(&secret_id(path))?.delete_credential() {
+                    Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                    Err(e) => Err(e).context("Failed to remove the secret key from the keyring"),
+                }
+            }
+            SecretStore::Encrypted => {
+                match std::fs::remove_file(path.with_extension(SECRET_EXTENSION)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        Err(e).context("Failed to remove the encrypted secret key")
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Saves the secret key to `store` first and then the credentials file
+    /// referencing it.
+    fn save(&self, path: &Path, creds: &Credentials, store: SecretStore) -> anyhow::Result<()> {
+        self.write_secret(path, store, &creds.storage_secret_key)?;
+        let mut stored = creds.clone();
+        stored.secret_store = store;
+        if store != SecretStore::Plaintext {
+            stored.storage_secret_key.clear();
+        }
+        stored.write_to_path(path)
+    }
+
+    pub fn remove(&self, user_storage_name: &str) -> anyhow::Result<()> {
+        let path = self.find_path(user_storage_name)?;
+        let creds = Credentials::read_from_path(&path)?;
+        self.delete_secret(&path, creds.secret_store)?;
+        std::fs::remove_file(path).context("Failed to remove credentials file")?;
         Ok(())
     }
 
+    /// Moves the secret key of a storage to `store`, returns false when it
+    /// is already kept there.
+    pub fn migrate(&self, user_storage_name: &str, store: SecretStore) -> anyhow::Result<bool> {
+        let path = self.find_path(user_storage_name)?;
+        let mut creds = Credentials::read_from_path(&path)?;
+        let old_store = creds.secret_store;
+        if old_store == store {
+            return Ok(false);
+        }
+        creds.storage_secret_key = self.read_secret(&path, &creds)?;
+        self.save(&path, &creds, store)?;
+        self.delete_secret(&path, old_store)?;
+        Ok(true)
+    }
+
+    pub fn rename(&self, user_storage_name: &str, new_name: &str) -> anyhow::Result<()> {
+        if new_name.is_empty() {
+            anyhow::bail!("User storage name cannot be empty");
+        }
+        if self
+            .get_all_stored()?
+            .iter()
+            .any(|c| c.user_storage_name == new_name)
+        {
+            anyhow::bail!("Credentials with the same name already exist");
+        }
+
+        let path = self.find_path(user_storage_name)?;
+        let mut creds = Credentials::read_from_path(&path)?;
+        creds.user_storage_name = new_name.to_owned();
+        creds.write_to_path(&path)
+    }
+
     pub fn add(&self, creds: Credentials) -> anyhow::Result<()> {
         if !self.base_path.exists() {
             std::fs::create_dir_all(&self.base_path)
@@ -57,7 +246,7 @@ impl CredentialsManager {
         }
 
         /* check if credentials with the same name do not exist already */
-        self.get_all().and_then(|existing_creds| {
+        self.get_all_stored().and_then(|existing_creds| {
             if existing_creds
                 .iter()
                 .any(|c| c.user_storage_name == creds.user_storage_name)
@@ -74,7 +263,7 @@ impl CredentialsManager {
                 hasher.finish()
             );
             let path = self.base_path.join(name);
-            creds.write_to_path(&path)
+            self.save(&path, &creds, self.secret_store)
         })
     }
 }
@@ -123,6 +312,22 @@ mod tests {
         assert!(all_creds.contains(&creds));
         assert!(all_creds.contains(&creds2));
 
+        assert_eq!(
+            creds_manager
+                .rename("test", "test2")
+                .err()
+                .unwrap()
+                .to_string(),
+            "Credentials with the same name already exist"
+        );
+        creds_manager.rename("test", "renamed").unwrap();
+        assert!(creds_manager
+            .get_all()
+            .unwrap()
+            .iter()
+            .any(|c| c.user_storage_name == "renamed"));
+        creds_manager.rename("renamed", "test").unwrap();
+
         creds_manager.remove("test").unwrap();
         let all_creds = creds_manager.get_all().unwrap();
         assert_eq!(all_creds.len(), 1);
@@ -142,4 +347,69 @@ mod tests {
         );
         assert_eq!(creds_manager.get_all().unwrap().len(), 0);
     }
+
+    #[test]
+    fn test_encrypted_secret_store() {
+        let temp_dir = tempdir().unwrap();
+        let creds_dir = temp_dir.path().join("creds");
+        let creds_manager = CredentialsManager::new(creds_dir.clone())
+            .with_secret_store(SecretStore::Encrypted)
+            .with_passphrase("passphrase".to_string());
+
+        let creds = Credentials::new_r2(
+            "test".to_string(),
+            "storage_name".to_string(),
+            "account_id".to_string(),
+            "access_key".to_string(),
+            "secret_key".to_string(),
+        );
+        creds_manager.add(creds.clone()).unwrap();
+
+        let files = |extension: &str| {
+            creds_dir
+                .read_dir()
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.extension().is_some_and(|e| e == extension))
+                .collect::<Vec<_>>()
+        };
+        let toml_path = files("toml").pop().unwrap();
+        assert!(!std::fs::read_to_string(&toml_path)
+            .unwrap()
+            .contains("secret_key"));
+        assert_eq!(files(SECRET_EXTENSION).len(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&toml_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert_eq!(
+            creds_manager.get_all_stored().unwrap()[0].storage_secret_key,
+            ""
+        );
+        let loaded = creds_manager.get("test").unwrap();
+        assert_eq!(loaded.storage_secret_key, "secret_key");
+        assert_eq!(loaded.secret_store, SecretStore::Encrypted);
+
+        let wrong_passphrase =
+            CredentialsManager::new(creds_dir.clone()).with_passphrase("wrong".to_string());
+        assert!(wrong_passphrase.get("test").is_err());
+
+        assert!(creds_manager
+            .migrate("test", SecretStore::Plaintext)
+            .unwrap());
+        assert!(!creds_manager
+            .migrate("test", SecretStore::Plaintext)
+            .unwrap());
+        assert_eq!(files(SECRET_EXTENSION).len(), 0);
+        assert_eq!(creds_manager.get_all_stored().unwrap(), vec![creds]);
+
+        assert!(creds_manager
+            .migrate("test", SecretStore::Encrypted)
+            .unwrap());
+        creds_manager.remove("test").unwrap();
+        assert_eq!(std::fs::read_dir(&creds_dir).unwrap().count(), 0);
+    }
 }
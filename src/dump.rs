@@ -0,0 +1,277 @@
+use anyhow::{self, Context};
+use object::elf;
+use object::write::elf::{FileHeader, ProgramHeader, SectionHeader, Writer};
+use object::Endianness;
+use probe_rs::Architecture;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A contiguous block of memory read back from the target.
+#[derive(Debug)]
+pub struct DumpRegion {
+    pub name: String,
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+/// Memory contents read back from a target by [`crate::dump_memory`].
+#[derive(Debug)]
+pub struct Dump {
+    pub chip: String,
+    pub architecture: Architecture,
+    pub regions: Vec<DumpRegion>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Bin,
+    Hex,
+    Elf,
+}
+
+impl DumpFormat {
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("bin") => Ok(DumpFormat::Bin),
+            Some("hex") | Some("ihex") => Ok(DumpFormat::Hex),
+            Some("elf") => Ok(DumpFormat::Elf),
+            _ => anyhow::bail!(
+                "Unknown dump format of {}, use a .bin, .hex or .elf extension",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Dump {
+    /// Writes the dump to `path` in the format given by its extension and
+    /// returns the paths of all written files.
+    ///
+    /// A `.bin` file can only hold a single region, so a dump of multiple
+    /// regions is split into one `<stem>_<region>.bin` file per region.
+    pub fn write_to_path(&self, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let written = match DumpFormat::from_path(path)? {
+            DumpFormat::Bin if self.regions.len() > 1 => {
+                let stem = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .context("Invalid dump file name")?;
+                let mut written = Vec::new();
+                for region in &self.regions {
+                    let region_path = path.with_file_name(format!(
+                        "{}_{}.bin",
+                        stem,
+                        region.name.replace(|c: char| !c.is_alphanumeric(), "_")
+                    ));
+                    fs::write(&region_path, &region.data)?;
+                    written.push(region_path);
+                }
+                written
+            }
+            DumpFormat::Bin => {
+                let data = self.regions.first().map_or(&[][..], |r| &r.data);
+                fs::write(path, data)?;
+                vec![path.to_path_buf()]
+            }
+            DumpFormat::Hex => {
+                fs::write(path, self.to_ihex()?)?;
+                vec![path.to_path_buf()]
+            }
+            DumpFormat::Elf => {
+                fs::write(path, self.to_elf()?)?;
+                vec![path.to_path_buf()]
+            }
+        };
+        Ok(written)
+    }
+
+    fn to_ihex(&self) -> anyhow::Result<String> {
+        let mut records = Vec::new();
+        for region in &self.regions {
+            if region.address + region.data.len() as u64 > 1 << 32 {
+                anyhow::bail!("Region {} does not fit into a HEX file", region.name);
+            }
+            let mut upper = None;
+            let mut offset = 0;
+            while offset < region.data.len() {
+                let address = region.address + offset as u64;
+                /* data records must not cross a 64 KiB boundary */
+                let len = (region.data.len() - offset)
+                    .min(16)
+                    .min((0x1_0000 - (address & 0xFFFF)) as usize);
+                if upper != Some(address >> 16) {
+                    upper = Some(address >> 16);
+                    records.push(ihex::Record::ExtendedLinearAddress((address >> 16) as u16));
+                }
+                records.push(ihex::Record::Data {
+                    offset: (address & 0xFFFF) as u16,
+                    value: region.data[offset..offset + len].to_vec(),
+                });
+                offset += len;
+            }
+        }
+        records.push(ihex::Record::EndOfFile);
+        ihex::create_object_file_representation(&records).context("Failed to encode HEX file")
+    }
+
+    fn to_elf(&self) -> anyhow::Result<Vec<u8>> {
+        let is_64 = self
+            .regions
+            .iter()
+            .any(|r| r.address + r.data.len() as u64 > u32::MAX as u64 + 1);
+        let e_machine = match (self.architecture, is_64) {
+            (Architecture::Arm, false) => elf::EM_ARM,
+            (Architecture::Arm, true) => elf::EM_AARCH64,
+            (Architecture::Riscv, _) => elf::EM_RISCV,
+            (Architecture::Xtensa, _) => elf::EM_XTENSA,
+        };
+
+        let section_names = self
+            .regions
+            .iter()
+            .map(|r| format!(".{}", r.name))
+            .collect::<Vec<String>>();
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Endianness::Little, is_64, &mut buffer);
+
+        /* reserve the file layout first, then write it out in the same order */
+        writer.reserve_file_header();
+        writer.reserve_program_headers(self.regions.len() as u32);
+        let offsets = self
+            .regions
+            .iter()
+            .map(|r| writer.reserve(r.data.len(), 4))
+            .collect::<Vec<usize>>();
+        writer.reserve_null_section_index();
+        let names = section_names
+            .iter()
+            .map(|n| {
+                let name = writer.add_section_name(n.as_bytes());
+                writer.reserve_section_index();
+                name
+            })
+            .collect::<Vec<_>>();
+        writer.reserve_shstrtab_section_index();
+        writer.reserve_shstrtab();
+        writer.reserve_section_headers();
+
+        writer.write_file_header(&FileHeader {
+            os_abi: elf::ELFOSABI_NONE,
+            abi_version: 0,
+            e_type: elf::ET_EXEC,
+            e_machine,
+            e_entry: 0,
+            e_flags: 0,
+        })?;
+        writer.write_align_program_headers();
+        for (region, offset) in self.regions.iter().zip(&offsets) {
+            writer.write_program_header(&ProgramHeader {
+                p_type: elf::PT_LOAD,
+                p_flags: elf::PF_R | elf::PF_X,
+                p_offset: *offset as u64,
+                p_vaddr: region.address,
+                p_paddr: region.address,
+                p_filesz: region.data.len() as u64,
+                p_memsz: region.data.len() as u64,
+                p_align: 4,
+            });
+        }
+        for (region, offset) in self.regions.iter().zip(&offsets) {
+            writer.pad_until(*offset);
+            writer.write(&region.data);
+        }
+        writer.write_shstrtab();
+        writer.write_null_section_header();
+        for ((region, offset), name) in self.regions.iter().zip(&offsets).zip(names) {
+            writer.write_section_header(&SectionHeader {
+                name: Some(name),
+                sh_type: elf::SHT_PROGBITS,
+                sh_flags: (elf::SHF_ALLOC | elf::SHF_EXECINSTR) as u64,
+                sh_addr: region.address,
+                sh_offset: *offset as u64,
+                sh_size: region.data.len() as u64,
+                sh_link: 0,
+                sh_info: 0,
+                sh_addralign: 4,
+                sh_entsize: 0,
+            });
+        }
+        writer.write_shstrtab_section_header();
+
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{Object, ObjectSegment};
+
+    fn test_dump() -> Dump {
+        Dump {
+            chip: "STM32L053R8Tx".to_string(),
+            architecture: Architecture::Arm,
+            regions: vec![
+                DumpRegion {
+                    name: "flash".to_string(),
+                    address: 0x0800_FFF8,
+                    data: (0..32).collect(),
+                },
+                DumpRegion {
+                    name: "eeprom".to_string(),
+                    address: 0x0808_0000,
+                    data: vec![0xAA; 4],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_dump_formats() {
+        let dump = test_dump();
+
+        let hex = dump.to_ihex().unwrap();
+        let mut data = Vec::new();
+        let mut upper = 0u64;
+        for record in ihex::Reader::new(&hex) {
+            match record.unwrap() {
+                ihex::Record::ExtendedLinearAddress(a) => upper = (a as u64) << 16,
+                ihex::Record::Data { offset, value } => data.push((upper + offset as u64, value)),
+                _ => {}
+            }
+        }
+        assert_eq!(data[0], (0x0800_FFF8, (0..8).collect()));
+        assert_eq!(data[1], (0x0801_0000, (8..24).collect()));
+        assert_eq!(data[2], (0x0801_0010, (24..32).collect()));
+        assert_eq!(data.last().unwrap(), &(0x0808_0000, vec![0xAA; 4]));
+
+        let elf = dump.to_elf().unwrap();
+        let file = object::File::parse(&*elf).unwrap();
+        let segments = file
+            .segments()
+            .map(|s| (s.address(), s.data().unwrap().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            dump.regions
+                .iter()
+                .map(|r| (r.address, r.data.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let written = dump
+            .write_to_path(&temp_dir.path().join("board.bin"))
+            .unwrap();
+        assert_eq!(
+            written,
+            vec![
+                temp_dir.path().join("board_flash.bin"),
+                temp_dir.path().join("board_eeprom.bin")
+            ]
+        );
+        assert!(dump
+            .write_to_path(&temp_dir.path().join("board.txt"))
+            .is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::storage::Firmware;
+use crate::{FlashOutcome, FlashReport};
+use anyhow::{self, Context};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashResult {
+    Programmed,
+    Unchanged,
+    Failed,
+}
+
+impl fmt::Display for FlashResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashResult::Programmed => write!(f, "programmed"),
+            FlashResult::Unchanged => write!(f, "unchanged"),
+            FlashResult::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A single entry of the flash history, one is recorded for every flashed board.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FlashRecord {
+    pub timestamp: i64,
+    pub user: String,
+    pub storage: String,
+    pub firmware_name: String,
+    pub firmware_version: String,
+    pub chip: String,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// Values written to the provisioning patch points of the firmware
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub provisioned: BTreeMap<String, String>,
+    pub probe: String,
+    #[serde(default)]
+    pub probe_alias: Option<String>,
+    pub result: FlashResult,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl FlashRecord {
+    pub fn new(
+        storage: &str,
+        firmware: &Firmware,
+        probe: String,
+        probe_alias: Option<String>,
+        result: &anyhow::Result<FlashReport>,
+    ) -> Self {
+        FlashRecord {
+            timestamp: Utc::now().timestamp(),
+            user: current_user(),
+            storage: storage.to_owned(),
+            firmware_name: firmware.name.clone(),
+            firmware_version: firmware.version.clone(),
+            chip: firmware.chip.clone(),
+            device_id: result.as_ref().ok().and_then(|r| r.device_id.clone()),
+            provisioned: result
+                .as_ref()
+                .map(|r| r.provisioned.clone())
+                .unwrap_or_default(),
+            probe,
+            probe_alias,
+            result: match result {
+                Ok(FlashReport {
+                    outcome: FlashOutcome::Programmed,
+                    ..
+                }) => FlashResult::Programmed,
+                Ok(FlashReport {
+                    outcome: FlashOutcome::Unchanged,
+                    ..
+                }) => FlashResult::Unchanged,
+                Err(_) => FlashResult::Failed,
+            },
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+/// Criteria for [`History::read_filtered`], unset fields match any record.
+#[derive(Default, Clone, Debug)]
+pub struct HistoryFilter {
+    /// Matches the target chip name or the device unique ID
+    pub board: Option<String>,
+    /// Matches the probe selector or alias
+    pub probe: Option<String>,
+    /// Matches the firmware name, or name and version as `name/version`
+    pub firmware: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, record: &FlashRecord) -> bool {
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+
+        self.board.as_ref().is_none_or(|b| {
+            contains(&record.chip, b) || record.device_id.as_ref().is_some_and(|id| contains(id, b))
+        }) && self
+            .probe
+            .as_ref()
+            .is_none_or(|p| contains(&record.probe, p) || record.probe_alias.as_deref() == Some(p))
+            && self
+                .firmware
+                .as_ref()
+                .is_none_or(|f| match f.split_once('/') {
+                    Some((name, version)) => {
+                        record.firmware_name == name && record.firmware_version == version
+                    }
+                    None => record.firmware_name == *f,
+                })
+            && self.since.is_none_or(|t| record.timestamp >= t)
+            && self.until.is_none_or(|t| record.timestamp < t)
+    }
+}
+
+/// Append-only log of flashed boards stored as JSON lines.
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: PathBuf) -> Self {
+        History { path }
+    }
+
+    pub fn append(&self, record: &FlashRecord) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("Failed to open flash history file")?;
+        writeln!(file, "{}", serde_json::to_string(record)?)
+            .context("Failed to write to flash history file")?;
+        Ok(())
+    }
+
+    pub fn read_all(&self) -> anyhow::Result<Vec<FlashRecord>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        fs::read_to_string(&self.path)
+            .context("Failed to read flash history file")?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).context("Failed to parse flash history record"))
+            .collect()
+    }
+
+    pub fn read_filtered(&self, filter: &HistoryFilter) -> anyhow::Result<Vec<FlashRecord>> {
+        Ok(self
+            .read_all()?
+            .into_iter()
+            .filter(|r| filter.matches(r))
+            .collect())
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes the records as CSV with a header row.
+pub fn write_csv(records: &[FlashRecord], path: &Path) -> anyhow::Result<()> {
+    let mut contents = String::from(
+        "timestamp,user,storage,firmware_name,firmware_version,chip,device_id,provisioned,probe,probe_alias,result,error\n",
+    );
+    for r in records {
+        let fields = [
+            chrono::DateTime::from_timestamp(r.timestamp, 0)
+                .map_or_else(|| r.timestamp.to_string(), |t| t.to_rfc3339()),
+            r.user.clone(),
+            r.storage.clone(),
+            r.firmware_name.clone(),
+            r.firmware_version.clone(),
+            r.chip.clone(),
+            r.device_id.clone().unwrap_or_default(),
+            r.provisioned
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>()
+                .join(";"),
+            r.probe.clone(),
+            r.probe_alias.clone().unwrap_or_default(),
+            r.result.to_string(),
+            r.error.clone().unwrap_or_default(),
+        ];
+        contents.push_str(
+            &fields
+                .iter()
+                .map(|f| csv_field(f))
+                .collect::<Vec<String>>()
+                .join(","),
+        );
+        contents.push('\n');
+    }
+    fs::write(path, contents).context("Failed to write CSV file")?;
+    Ok(())
+}
+
+/// Name of the user running the tool, as far as the environment tells.
+pub fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn record(timestamp: i64, firmware_version: &str, result: FlashResult) -> FlashRecord {
+        FlashRecord {
+            timestamp,
+            user: "tester".to_string(),
+            storage: "r2".to_string(),
+            firmware_name: "blinky".to_string(),
+            firmware_version: firmware_version.to_string(),
+            chip: "STM32L053R8Tx".to_string(),
+            device_id: Some("2E0031000F51383138393538".to_string()),
+            provisioned: BTreeMap::from([("serial".to_string(), timestamp.to_string())]),
+            probe: "0483:374B:066DFF48".to_string(),
+            probe_alias: Some("rig-3-left".to_string()),
+            result,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("history.jsonl");
+        let history = History::new(path.clone());
+        assert_eq!(history.read_all().unwrap().len(), 0);
+
+        let mut failed = record(200, "fast", FlashResult::Failed);
+        failed.error = Some("Failed to attach probe, \"timeout\"".to_string());
+        history
+            .append(&record(100, "slow", FlashResult::Programmed))
+            .unwrap();
+        history.append(&failed).unwrap();
+        assert_eq!(history.read_all().unwrap().len(), 2);
+
+        let filtered = |filter: HistoryFilter| history.read_filtered(&filter).unwrap().len();
+        assert_eq!(filtered(HistoryFilter::default()), 2);
+        assert_eq!(
+            filtered(HistoryFilter {
+                firmware: Some("blinky/fast".to_string()),
+                ..Default::default()
+            }),
+            1
+        );
+        assert_eq!(
+            filtered(HistoryFilter {
+                probe: Some("rig-3-left".to_string()),
+                board: Some("stm32l0".to_string()),
+                ..Default::default()
+            }),
+            2
+        );
+        assert_eq!(
+            filtered(HistoryFilter {
+                board: Some("2E0031000F51".to_string()),
+                ..Default::default()
+            }),
+            2
+        );
+        assert_eq!(
+            filtered(HistoryFilter {
+                probe: Some("0483:374b".to_string()),
+                since: Some(150),
+                ..Default::default()
+            }),
+            1
+        );
+        assert_eq!(
+            filtered(HistoryFilter {
+                until: Some(100),
+                ..Default::default()
+            }),
+            0
+        );
+
+        let csv_path = temp_dir.path().join("history.csv");
+        write_csv(&history.read_all().unwrap(), &csv_path).unwrap();
+        let csv = fs::read_to_string(csv_path).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv
+            .lines()
+            .last()
+            .unwrap()
+            .ends_with(",failed,\"Failed to attach probe, \"\"timeout\"\"\""));
+    }
+}
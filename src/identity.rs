@@ -0,0 +1,241 @@
+use anyhow::{self, Context};
+use probe_rs::{Core, MemoryInterface};
+use std::fmt;
+
+/// Identity of the connected chip as read from its identification registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipIdentity {
+    /// Part number from the nRF52 FICR, e.g. 0x52840
+    Nrf52 { part: u32 },
+    /// Device ID from the STM32 DBGMCU_IDCODE register
+    Stm32 { dev_id: u16 },
+}
+
+const NRF52_FICR_INFO_PART: u64 = 0x1000_0100;
+
+/// STM32 device IDs and the prefixes of the chip names they belong to.
+const STM32_DEVICES: &[(u16, &[&str])] = &[
+    (0x410, &["STM32F1"]),
+    (0x412, &["STM32F1"]),
+    (0x414, &["STM32F1"]),
+    (0x418, &["STM32F1"]),
+    (0x430, &["STM32F1"]),
+    (0x440, &["STM32F05", "STM32F030"]),
+    (0x442, &["STM32F09", "STM32F030"]),
+    (0x444, &["STM32F03"]),
+    (0x445, &["STM32F04", "STM32F070"]),
+    (0x448, &["STM32F07"]),
+    (0x413, &["STM32F405", "STM32F407", "STM32F415", "STM32F417"]),
+    (0x419, &["STM32F42", "STM32F43"]),
+    (0x423, &["STM32F401"]),
+    (0x433, &["STM32F401"]),
+    (0x431, &["STM32F411"]),
+    (0x421, &["STM32F446"]),
+    (0x441, &["STM32F412"]),
+    (0x463, &["STM32F413", "STM32F423"]),
+    (0x434, &["STM32F469", "STM32F479"]),
+    (0x449, &["STM32F74", "STM32F75"]),
+    (0x451, &["STM32F76", "STM32F77"]),
+    (0x452, &["STM32F72", "STM32F73"]),
+    (0x457, &["STM32L01", "STM32L02"]),
+    (0x425, &["STM32L03", "STM32L04"]),
+    (0x417, &["STM32L05", "STM32L06"]),
+    (0x447, &["STM32L07", "STM32L08"]),
+    (0x464, &["STM32L41", "STM32L42"]),
+    (0x435, &["STM32L43", "STM32L44"]),
+    (0x462, &["STM32L45", "STM32L46"]),
+    (0x415, &["STM32L47", "STM32L48"]),
+    (0x461, &["STM32L49", "STM32L4A"]),
+    (0x470, &["STM32L4R", "STM32L4S"]),
+    (0x471, &["STM32L4P", "STM32L4Q"]),
+    (0x472, &["STM32L55", "STM32L56"]),
+    (0x482, &["STM32U57", "STM32U58"]),
+    (0x443, &["STM32C011"]),
+    (0x453, &["STM32C031"]),
+    (0x466, &["STM32G03", "STM32G04"]),
+    (0x456, &["STM32G05", "STM32G06"]),
+    (0x460, &["STM32G07", "STM32G08"]),
+    (0x467, &["STM32G0B", "STM32G0C"]),
+    (0x468, &["STM32G431", "STM32G441"]),
+    (0x469, &["STM32G47", "STM32G48"]),
+    (0x479, &["STM32G491", "STM32G4A1"]),
+    (0x450, &["STM32H74", "STM32H75"]),
+    (0x480, &["STM32H7A", "STM32H7B"]),
+    (0x483, &["STM32H72", "STM32H73"]),
+    (0x495, &["STM32WB5"]),
+    (0x497, &["STM32WLE", "STM32WL5"]),
+];
+
+/// Address of the DBGMCU_IDCODE register, which depends on the family.
+fn stm32_idcode_address(chip: &str) -> u64 {
+    let family = chip.get(..7).unwrap_or_default();
+    match family {
+        "STM32F0" | "STM32L0" | "STM32G0" | "STM32C0" => 0x4001_5800,
+        "STM32H7" => 0x5C00_1000,
+        "STM32L5" | "STM32U5" => 0xE004_4000,
+        _ => 0xE004_2000,
+    }
+}
+
+/// Addresses of DBGMCU_IDCODE across the STM32 families.
+const STM32_IDCODE_ADDRESSES: &[u64] = &[0xE004_2000, 0x4001_5800, 0x5C00_1000, 0xE004_4000];
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len())
+        .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+}
+
+impl ChipIdentity {
+    /// Reads the identity of a chip from the family of `chip`, returns
+    /// `None` for families without a known identification register.
+    pub fn read(core: &mut Core, chip: &str) -> anyhow::Result<Option<Self>> {
+        let chip = chip.to_uppercase();
+        let identity = if chip.starts_with("NRF52") {
+            let part = core.read_word_32(NRF52_FICR_INFO_PART)?;
+            ChipIdentity::Nrf52 { part }
+        } else if chip.starts_with("STM32") {
+            let idcode = core.read_word_32(stm32_idcode_address(&chip))?;
+            ChipIdentity::Stm32 {
+                dev_id: (idcode & 0xFFF) as u16,
+            }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(identity))
+    }
+
+    /// Looks for a recognized identity in the identification registers of
+    /// all known families, for chips that are not of the expected family.
+    pub fn detect(core: &mut Core) -> Option<Self> {
+        let nrf52 = core
+            .read_word_32(NRF52_FICR_INFO_PART)
+            .map(|part| ChipIdentity::Nrf52 { part });
+        let stm32 = STM32_IDCODE_ADDRESSES.iter().map(|&address| {
+            core.read_word_32(address)
+                .map(|idcode| ChipIdentity::Stm32 {
+                    dev_id: (idcode & 0xFFF) as u16,
+                })
+        });
+        std::iter::once(nrf52)
+            .chain(stm32.collect::<Vec<_>>())
+            .flatten()
+            .find(|identity| identity.is_known())
+    }
+
+    /// Whether the identity was recognized, unknown identities can not be
+    /// compared against chip names.
+    pub fn is_known(&self) -> bool {
+        match self {
+            ChipIdentity::Nrf52 { part } => part >> 12 == 0x52,
+            ChipIdentity::Stm32 { dev_id } => STM32_DEVICES.iter().any(|(id, _)| id == dev_id),
+        }
+    }
+
+    /// Whether the identity belongs to a chip named `chip` in probe-rs.
+    pub fn matches(&self, chip: &str) -> bool {
+        match self {
+            ChipIdentity::Nrf52 { part } => {
+                starts_with_ignore_case(chip, &format!("nRF{:X}", part))
+            }
+            ChipIdentity::Stm32 { dev_id } => STM32_DEVICES
+                .iter()
+                .filter(|(id, _)| id == dev_id)
+                .flat_map(|(_, prefixes)| prefixes.iter())
+                .any(|prefix| starts_with_ignore_case(chip, prefix)),
+        }
+    }
+}
+
+impl fmt::Display for ChipIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChipIdentity::Nrf52 { part } => write!(f, "nRF{:X}", part),
+            ChipIdentity::Stm32 { dev_id } => {
+                let names = STM32_DEVICES
+                    .iter()
+                    .filter(|(id, _)| id == dev_id)
+                    .flat_map(|(_, prefixes)| prefixes.iter().map(|p| format!("{}*", p)))
+                    .collect::<Vec<String>>();
+                match names.is_empty() {
+                    true => write!(f, "STM32 with device ID {:#05x}", dev_id),
+                    false => write!(f, "{} (device ID {:#05x})", names.join("/"), dev_id),
+                }
+            }
+        }
+    }
+}
+
+/// Checks that the connected chip is `chip` or one of `compatible_chips`.
+///
+/// Returns a description of the check for the user, or an error when the
+/// connected chip is identified as something else or its identity can not be
+/// confirmed. When the family of `chip` has no known identification register
+/// the description is a warning.
+pub fn check_chip(
+    core: &mut Core,
+    chip: &str,
+    compatible_chips: &[String],
+) -> anyhow::Result<String> {
+    let identity = match ChipIdentity::read(core, chip) {
+        Ok(Some(identity)) if identity.is_known() => Ok(identity),
+        Ok(Some(identity)) => Err(format!("Unrecognized chip identity {}", identity)),
+        Ok(None) => {
+            return Ok(format!(
+                "Warning: unable to verify the identity of {} chips",
+                chip
+            ))
+        }
+        Err(e) => Err(format!("Unable to read the chip identity ({})", e)),
+    };
+    /* the identification register of the expected family gave nothing
+     * useful, the chip may be of another family */
+    let identity = match identity.or_else(|reason| ChipIdentity::detect(core).ok_or(reason)) {
+        Ok(identity) => identity,
+        Err(reason) => {
+            return Err(anyhow::anyhow!(reason)).context(format!(
+                "Unable to confirm the connected chip is {} (use --force to flash anyway)",
+                chip
+            ))
+        }
+    };
+
+    if identity.matches(chip) || compatible_chips.iter().any(|c| identity.matches(c)) {
+        return Ok(format!("Connected chip is {}", identity));
+    }
+    Err(anyhow::anyhow!(
+        "Connected chip is {}, but the firmware is built for {}{}",
+        identity,
+        chip,
+        match compatible_chips.is_empty() {
+            true => String::new(),
+            false => format!(" (compatible with {})", compatible_chips.join(", ")),
+        }
+    ))
+    .context("Chip mismatch (use --force to flash anyway)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_matching() {
+        let nrf = ChipIdentity::Nrf52 { part: 0x52840 };
+        assert!(nrf.matches("nRF52840_xxAA"));
+        assert!(!nrf.matches("nRF52832_xxAA"));
+        assert_eq!(nrf.to_string(), "nRF52840");
+
+        let stm32 = ChipIdentity::Stm32 { dev_id: 0x460 };
+        assert!(stm32.matches("STM32G071RBTx"));
+        assert!(stm32.matches("stm32g081rbix"));
+        assert!(!stm32.matches("STM32G031K8Tx"));
+        assert!(!stm32.matches("STM32L053R8Tx"));
+        assert_eq!(stm32.to_string(), "STM32G07*/STM32G08* (device ID 0x460)");
+
+        let unknown = ChipIdentity::Stm32 { dev_id: 0xFFF };
+        assert!(!unknown.is_known());
+        assert!(!ChipIdentity::Nrf52 { part: 0xFFFF_FFFF }.is_known());
+        assert_eq!(stm32_idcode_address("STM32G071RBTx"), 0x4001_5800);
+        assert_eq!(stm32_idcode_address("STM32F411CEUx"), 0xE004_2000);
+    }
+}
@@ -1,21 +1,49 @@
 use anyhow::{self, Context};
+use dump::{Dump, DumpRegion};
 use etcetera::{self, AppStrategy, AppStrategyArgs};
+use monitor::{ChannelDecoder, RttReader};
 use probe_rs::{
-    flashing::{download_file_with_options, DownloadOptions, FlashProgress, Format, ProgressEvent},
-    probe::{list::Lister, DebugProbeInfo, Probe},
-    Permissions,
+    config::{ChipFamily, Registry},
+    flashing::{
+        build_loader, erase, erase_all, DownloadOptions, ElfOptions, FlashError, FlashProgress,
+        Format, ProgressEvent, ProgressOperation,
+    },
+    probe::{list::Lister, DebugProbeError, DebugProbeInfo, Probe},
+    MemoryInterface, Permissions, Session, VectorCatchCondition,
 };
-use std::{fs, path::PathBuf};
+use provisioning::Provisioner;
+use runner::{RunOptions, RunOutcome};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use storage::Firmware;
+pub use utils::parse_number;
 
 pub mod credentials;
 pub mod credentials_manager;
+pub mod dump;
+pub mod history;
+pub mod identity;
+pub mod monitor;
+pub mod option_bytes;
+pub mod probes;
+pub mod provisioning;
+pub mod runner;
+pub mod secrets;
 pub mod storage;
 mod utils;
+pub mod versions;
 
 pub struct BaseDirs {
     pub creds_dir: PathBuf,
     pub firmware_cache_dir: PathBuf,
+    pub probe_aliases_path: PathBuf,
+    pub history_path: PathBuf,
+    pub provisioning_ledger_path: PathBuf,
 }
 
 impl BaseDirs {
@@ -29,14 +57,21 @@ impl BaseDirs {
 
         let creds_dir = strategy.config_dir().join("credentials");
         let firmware_cache_dir = strategy.cache_dir().join("firmware");
+        let probe_aliases_path = strategy.config_dir().join("probe-aliases.toml");
+        let history_path = strategy.data_dir().join("history.jsonl");
+        let provisioning_ledger_path = strategy.data_dir().join("provisioning.jsonl");
 
         fs::create_dir_all(&creds_dir).context("Failed to create config directory")?;
         fs::create_dir_all(&firmware_cache_dir)
             .context("Failed to create firmware cache directory")?;
+        fs::create_dir_all(strategy.data_dir()).context("Failed to create data directory")?;
 
         Ok(BaseDirs {
             creds_dir,
             firmware_cache_dir,
+            probe_aliases_path,
+            history_path,
+            provisioning_ledger_path,
         })
     }
 
@@ -57,38 +92,688 @@ pub fn get_probes() -> anyhow::Result<Vec<DebugProbeInfo>> {
     Ok(probes)
 }
 
+/// Options controlling how [`flash_firmware`] programs the target.
+#[derive(Default, Debug, Clone)]
+pub struct FlashOptions {
+    /// Assert the nreset & ntrst pins during attaching the probe to the chip
+    pub connect_under_reset: bool,
+    /// Compare the target flash with the firmware image first and skip
+    /// erasing and programming when they are identical
+    pub if_changed: bool,
+    /// Assigns the values of the provisioning patch points declared in the
+    /// firmware manifest, required when the manifest declares any
+    pub provisioner: Option<Arc<Provisioner>>,
+    /// Flash even if the connected chip does not match the firmware manifest
+    pub force: bool,
+}
+
+/// Result of a successful [`flash_firmware`] call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashReport {
+    pub outcome: FlashOutcome,
+    /// Unique ID of the device as a hex string, if the firmware manifest declares where to read it
+    pub device_id: Option<String>,
+    /// Values written to the provisioning patch points, by patch point name
+    pub provisioned: BTreeMap<String, String>,
+}
+
+/// What [`flash_firmware`] ended up doing with the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashOutcome {
+    /// The target was erased and programmed with the firmware image.
+    Programmed,
+    /// The target already held the firmware image, nothing was written.
+    Unchanged,
+}
+
+/// Chip families registered by [`register_target_description`] on top of
+/// the probe-rs built-in targets.
+static CUSTOM_TARGETS: Mutex<Vec<ChipFamily>> = Mutex::new(Vec::new());
+
+/// Registers the chips of a probe-rs target description YAML file, such as
+/// the ones generated from a CMSIS-Pack by `target-gen`, so they can be
+/// attached to. Returns the name of the registered chip family.
+pub fn register_target_description(path: &Path) -> anyhow::Result<String> {
+    let yaml = fs::read_to_string(path).context(format!(
+        "Failed to read target description {}",
+        path.display()
+    ))?;
+    let mut registry = Registry::new();
+    let family = registry
+        .add_target_family_from_yaml(&yaml)
+        .context(format!("Invalid target description {}", path.display()))?;
+
+    let mut targets = CUSTOM_TARGETS.lock().unwrap();
+    targets.retain(|f| f.name != family);
+    targets.extend(registry.families().iter().cloned());
+    Ok(family)
+}
+
+fn registry() -> anyhow::Result<Registry> {
+    let mut registry = Registry::from_builtin_families();
+    for family in CUSTOM_TARGETS.lock().unwrap().iter() {
+        registry.add_target_family(family.clone())?;
+    }
+    Ok(registry)
+}
+
+fn attach(
+    probe: Probe,
+    chip: &str,
+    connect_under_reset: bool,
+    permissions: Permissions,
+) -> anyhow::Result<Session> {
+    let registry = registry()?;
+    match connect_under_reset {
+        true => probe.attach_under_reset_with_registry(chip, permissions, &registry),
+        false => probe.attach_with_registry(chip, permissions, &registry),
+    }
+    .context("Failed to attach probe")
+}
+
 pub fn flash_firmware(
     probe: Probe,
     firmware: Firmware,
+    flash_options: &FlashOptions,
+    progress_callback: &dyn Fn(String),
+) -> anyhow::Result<FlashReport> {
+    progress_callback("Attaching to target...".to_string());
+    let mut session = attach(
+        probe,
+        &firmware.chip,
+        flash_options.connect_under_reset,
+        Permissions::default(),
+    )?;
+    flash_attached(&mut session, &firmware, flash_options, progress_callback)
+}
+
+/// Does the work of [`flash_firmware`] on an already attached session.
+fn flash_attached(
+    session: &mut Session,
+    firmware: &Firmware,
+    flash_options: &FlashOptions,
+    progress_callback: &dyn Fn(String),
+) -> anyhow::Result<FlashReport> {
+    match identity::check_chip(
+        &mut session.core(0)?,
+        &firmware.chip,
+        &firmware.compatible_chips,
+    ) {
+        Ok(message) => progress_callback(message),
+        Err(e) if flash_options.force => {
+            progress_callback(format!("Warning: {:#}, flashing anyway", e))
+        }
+        Err(e) => return Err(e),
+    }
+
+    let device_id = match firmware.unique_id {
+        Some(location) => {
+            let mut id = vec![0u8; location.length];
+            session
+                .core(0)?
+                .read(location.address, &mut id)
+                .context("Failed to read the device unique ID")?;
+            let id = id.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+            progress_callback(format!("Device ID: {}", id));
+            Some(id)
+        }
+        None => None,
+    };
+
+    let option_writes = option_bytes::plan_writes(&firmware.chip, &firmware.option_bytes)
+        .context("Invalid option bytes in the firmware manifest")?;
+
+    let provisioned = match (&flash_options.provisioner, firmware.provisioning.is_empty()) {
+        (_, true) => BTreeMap::new(),
+        (Some(provisioner), false) => {
+            let values = provisioner.allocate(firmware, device_id.as_deref())?;
+            for (name, value) in &values {
+                progress_callback(format!("Provisioning {} = {}", name, value));
+            }
+            values
+        }
+        (None, false) => anyhow::bail!(
+            "Firmware {}/{} declares provisioning patch points but no provisioner was given",
+            firmware.name,
+            firmware.version
+        ),
+    };
+
+    let result = program_firmware(
+        session,
+        firmware,
+        &provisioned,
+        flash_options,
+        progress_callback,
+    );
+    if let Some(provisioner) = &flash_options.provisioner {
+        match result {
+            Ok(FlashOutcome::Programmed) if !provisioned.is_empty() => {
+                provisioner.commit(firmware, device_id.as_deref(), &provisioned)?
+            }
+            _ => provisioner.release(&provisioned),
+        }
+    }
+    let outcome = result?;
+
+    let reset = option_bytes::apply_writes(session, &option_writes, false, progress_callback)
+        .context("Failed to write option bytes")?;
+
+    if !reset {
+        progress_callback("Resetting target...".to_string());
+        session.core(0)?.reset()?;
+    }
+
+    Ok(FlashReport {
+        outcome,
+        device_id,
+        provisioned,
+    })
+}
+
+/// Reports the option byte writes declared in the firmware manifest against
+/// the current values of the target, without programming or resetting it.
+pub fn preview_option_bytes(
+    probe: Probe,
+    firmware: &Firmware,
     connect_under_reset: bool,
-    progress_callback: &'static dyn Fn(String),
+    progress_callback: &dyn Fn(String),
 ) -> anyhow::Result<()> {
-    // Attach to a chip.
+    let option_writes = option_bytes::plan_writes(&firmware.chip, &firmware.option_bytes)
+        .context("Invalid option bytes in the firmware manifest")?;
+    if option_writes.is_empty() {
+        progress_callback("The firmware manifest declares no option bytes".to_string());
+        return Ok(());
+    }
+
     progress_callback("Attaching to target...".to_string());
-    let mut session = match connect_under_reset {
-        true => probe.attach_under_reset(&firmware.chip, Permissions::default()),
-        false => probe.attach(&firmware.chip, Permissions::default()),
+    let mut session = attach(
+        probe,
+        &firmware.chip,
+        connect_under_reset,
+        Permissions::default(),
+    )?;
+    option_bytes::apply_writes(&mut session, &option_writes, true, progress_callback)?;
+    Ok(())
+}
+
+fn program_firmware(
+    session: &mut Session,
+    firmware: &Firmware,
+    provisioned: &BTreeMap<String, String>,
+    flash_options: &FlashOptions,
+    progress_callback: &dyn Fn(String),
+) -> anyhow::Result<FlashOutcome> {
+    let loader = match provisioned.is_empty() {
+        true => build_loader(
+            session,
+            &firmware.path,
+            Format::Elf(ElfOptions::default()),
+            None,
+        )
+        .context("Failed to load firmware image")?,
+        false => {
+            let mut image = fs::read(&firmware.path).context("Failed to read firmware image")?;
+            provisioning::patch_image(&mut image, &firmware.provisioning, provisioned)
+                .context("Failed to patch firmware image")?;
+            let mut loader = session.target().flash_loader();
+            loader
+                .load_image(
+                    session,
+                    &mut Cursor::new(image),
+                    Format::Elf(ElfOptions::default()),
+                    None,
+                )
+                .context("Failed to load firmware image")?;
+            loader
+        }
+    };
+
+    let mut outcome = FlashOutcome::Programmed;
+    if flash_options.if_changed {
+        progress_callback("Comparing target flash with the firmware image...".to_string());
+        match loader.verify(session, &mut FlashProgress::empty()) {
+            Ok(()) => outcome = FlashOutcome::Unchanged,
+            Err(FlashError::Verify) => {
+                progress_callback("Target flash differs from the firmware image".to_string())
+            }
+            Err(e) => progress_callback(format!("Unable to compare target flash ({})", e)),
+        }
     }
-    .context("Failed to attach probe")?;
 
-    // Download the firmware binary.
-    progress_callback(format!(
-        "Downloading {}/{} to target chip {}...",
-        firmware.name, firmware.version, firmware.chip
-    ));
-    let mut options = DownloadOptions::default();
-    options.progress = Some(FlashProgress::new(|e| match e {
-        ProgressEvent::StartedErasing => progress_callback("Flash erasing...".to_string()),
-        ProgressEvent::FinishedErasing => progress_callback("Flash programming...".to_string()),
-        _ => {}
-    }));
-    options.verify = true;
-    options.do_chip_erase = true;
-    download_file_with_options(&mut session, firmware.path, Format::Elf, options)
-        .context("Failed to flash firmware")?;
+    if outcome == FlashOutcome::Programmed {
+        // Download the firmware binary.
+        progress_callback(format!(
+            "Downloading {}/{} to target chip {}...",
+            firmware.name, firmware.version, firmware.chip
+        ));
+        let mut options = DownloadOptions::default();
+        options.progress = FlashProgress::new(|e| match e {
+            ProgressEvent::Started(ProgressOperation::Erase) => {
+                progress_callback("Flash erasing...".to_string())
+            }
+            ProgressEvent::Started(ProgressOperation::Program) => {
+                progress_callback("Flash programming...".to_string())
+            }
+            _ => {}
+        });
+        options.verify = true;
+        options.do_chip_erase = true;
+        loader
+            .commit(session, options)
+            .context("Failed to flash firmware")?;
+    } else {
+        progress_callback(format!(
+            "Target already holds {}/{}, skipping programming",
+            firmware.name, firmware.version
+        ));
+    }
+
+    Ok(outcome)
+}
+
+/// Flashes the same firmware onto all `probes` concurrently, each probe is
+/// opened and flashed in its own thread.
+///
+/// The progress callback receives the index of the probe the message is
+/// about, the results are returned in the order of `probes`.
+pub fn flash_firmware_on_probes(
+    probes: &[&DebugProbeInfo],
+    firmware: &Firmware,
+    flash_options: &FlashOptions,
+    progress_callback: &(dyn Fn(usize, String) + Sync),
+) -> Vec<anyhow::Result<FlashReport>> {
+    std::thread::scope(|scope| {
+        let handles = probes
+            .iter()
+            .enumerate()
+            .map(|(i, info)| {
+                let firmware = firmware.clone();
+                scope.spawn(move || {
+                    let probe = info.open().context("Failed to open probe")?;
+                    flash_firmware(probe, firmware, flash_options, &|s| progress_callback(i, s))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Flashing thread panicked")))
+            })
+            .collect()
+    })
+}
+
+/// Whether an error attaching to a target will not go away by connecting
+/// another board, as opposed to errors caused by no target being present.
+fn is_persistent_attach_error(error: &probe_rs::Error) -> bool {
+    match error {
+        probe_rs::Error::Probe(e) => matches!(
+            e,
+            DebugProbeError::Usb(_)
+                | DebugProbeError::ProbeCouldNotBeCreated(_)
+                | DebugProbeError::UnsupportedProtocol(_)
+                | DebugProbeError::UnsupportedSpeed(_)
+                | DebugProbeError::InterfaceNotAvailable { .. }
+        ),
+        probe_rs::Error::ChipNotFound(_) | probe_rs::Error::MissingPermissions(_) => true,
+        _ => false,
+    }
+}
+
+/// Blocks until a target can be attached to through the probe (if `present`
+/// is true) or until it can no longer be attached to (if `present` is false).
+///
+/// The target is polled without connecting under reset, so that a running
+/// board is not reset on every poll. Returns an error when the chip is not
+/// known or the probe itself fails, e.g. because it was disconnected.
+pub fn wait_for_target(probe: &DebugProbeInfo, chip: &str, present: bool) -> anyhow::Result<()> {
+    registry()?
+        .get_target_by_name(chip)
+        .context(format!("Unknown chip \"{}\"", chip))?;
+    loop {
+        let probe = probe
+            .open()
+            .context("Failed to open probe, was it disconnected?")?;
+        let attachable = match attach(probe, chip, false, Permissions::default()) {
+            Ok(_) => true,
+            Err(e) => match e.downcast_ref::<probe_rs::Error>() {
+                Some(error) if is_persistent_attach_error(error) => return Err(e),
+                _ => false,
+            },
+        };
+        if attachable == present {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Reads the given memory `range` of the target, or every flash region of its
+/// memory map if no range is given.
+pub fn dump_memory(
+    probe: Probe,
+    chip: &str,
+    connect_under_reset: bool,
+    range: Option<Range<u64>>,
+    progress_callback: &dyn Fn(String),
+) -> anyhow::Result<Dump> {
+    progress_callback("Attaching to target...".to_string());
+    let mut session = attach(probe, chip, connect_under_reset, Permissions::default())?;
+    let target = session.target().clone();
+
+    let regions = match range {
+        Some(range) => {
+            /* the range is read in one go, so it has to be a sane size */
+            let region = target
+                .memory_map
+                .iter()
+                .find(|r| {
+                    let region = r.address_range();
+                    region.start <= range.start && range.end <= region.end
+                })
+                .context(format!(
+                    "Memory range {:#010x}..{:#010x} is not within a memory region of {}",
+                    range.start, range.end, target.name
+                ))?;
+            let core = target
+                .cores
+                .iter()
+                .position(|c| region.cores().contains(&c.name))
+                .unwrap_or(0);
+            vec![(format!("{:08x}", range.start), range, core)]
+        }
+        None => target
+            .memory_map
+            .iter()
+            .filter_map(|r| r.as_nvm_region())
+            .filter(|r| !r.is_alias)
+            .map(|r| {
+                let core = target
+                    .cores
+                    .iter()
+                    .position(|c| r.accessible_by(&c.name))
+                    .unwrap_or(0);
+                let name = r
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("{:08x}", r.range.start));
+                (name, r.range.clone(), core)
+            })
+            .collect(),
+    };
+
+    let mut dump = Dump {
+        chip: target.name.clone(),
+        architecture: target.architecture(),
+        regions: Vec::new(),
+    };
+    for (name, range, core) in regions {
+        progress_callback(format!(
+            "Reading {} ({:#010x}..{:#010x})...",
+            name, range.start, range.end
+        ));
+        let mut data = vec![0u8; (range.end - range.start) as usize];
+        session
+            .core(core)?
+            .read(range.start, &mut data)
+            .context(format!("Failed to read memory region {}", name))?;
+        dump.regions.push(DumpRegion {
+            name,
+            address: range.start,
+            data,
+        });
+    }
+
+    Ok(dump)
+}
+
+/// What part of the target [`erase_flash`] erases.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EraseMode {
+    /// Erase the flash sectors covering the address range.
+    Range(Range<u64>),
+    /// Erase all flash regions of the target.
+    Chip,
+    /// Unlock a read-protected target and erase all of its memory, this may
+    /// irreversibly remove security keys or reset the target to factory defaults.
+    Unlock,
+}
+
+pub fn erase_flash(
+    probe: Probe,
+    chip: &str,
+    connect_under_reset: bool,
+    mode: EraseMode,
+    progress_callback: &dyn Fn(String),
+) -> anyhow::Result<()> {
+    let permissions = match mode {
+        EraseMode::Unlock => Permissions::new().allow_erase_all(),
+        _ => Permissions::default(),
+    };
+
+    progress_callback("Attaching to target...".to_string());
+    let mut session = attach(probe, chip, connect_under_reset, permissions)?;
+
+    let mut progress = FlashProgress::empty();
+    match mode {
+        EraseMode::Range(range) => {
+            progress_callback(format!(
+                "Erasing sectors in {:#010x}..{:#010x}...",
+                range.start, range.end
+            ));
+            erase(&mut session, &mut progress, range.start, range.end, false)
+                .context("Failed to erase flash range")?;
+        }
+        EraseMode::Chip => {
+            progress_callback("Erasing all flash...".to_string());
+            erase_all(&mut session, &mut progress, false).context("Failed to erase flash")?;
+        }
+        EraseMode::Unlock if session.has_sequence_erase_all() => {
+            progress_callback("Unlocking and erasing target...".to_string());
+            session
+                .sequence_erase_all()
+                .context("Failed to run the target erase sequence")?;
+        }
+        EraseMode::Unlock => anyhow::bail!(
+            "{} has no unlock sequence, its read protection can not be removed this way",
+            chip
+        ),
+    }
 
     progress_callback("Resetting target...".to_string());
     session.core(0)?.reset()?;
 
     Ok(())
 }
+
+/// Attaches to the running target and streams the RTT up channels of the
+/// firmware to `line_callback` along with the channel name, defmt frames are
+/// decoded using the firmware ELF file. Only returns on error.
+pub fn monitor_rtt(
+    probe: Probe,
+    firmware: &Firmware,
+    progress_callback: &dyn Fn(String),
+    line_callback: &mut dyn FnMut(&str, String),
+) -> anyhow::Result<()> {
+    let elf = fs::read(&firmware.path).context("Failed to read firmware image")?;
+    let table = defmt_decoder::Table::parse(&elf).context("Failed to parse defmt data")?;
+
+    progress_callback("Attaching to RTT...".to_string());
+    let mut session = attach(probe, &firmware.chip, false, Permissions::default())?;
+    let mut core = session.core(0)?;
+    let mut rtt = RttReader::attach(&mut core, &elf, table.as_ref(), Duration::from_secs(3))?;
+    progress_callback(format!(
+        "Monitoring RTT channels {}{}",
+        rtt.channel_names().join(", "),
+        if table.is_some() { " (defmt)" } else { "" }
+    ));
+
+    loop {
+        if !rtt.poll(&mut core, line_callback)? {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Result of a successful [`run_firmware`] call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    pub flash: FlashReport,
+    pub outcome: RunOutcome,
+}
+
+/// Flashes the firmware and lets it run, answering its semihosting requests
+/// until it exits or the timeout passes.
+///
+/// Semihosting console output (and the RTT channels if enabled) is passed
+/// to `line_callback` along with the channel name.
+pub fn run_firmware(
+    probe: Probe,
+    firmware: Firmware,
+    flash_options: &FlashOptions,
+    run_options: &RunOptions,
+    progress_callback: &dyn Fn(String),
+    line_callback: &mut dyn FnMut(&str, String),
+) -> anyhow::Result<RunReport> {
+    progress_callback("Attaching to target...".to_string());
+    let mut session = attach(
+        probe,
+        &firmware.chip,
+        flash_options.connect_under_reset,
+        Permissions::default(),
+    )?;
+    let flash = flash_attached(&mut session, &firmware, flash_options, progress_callback)?;
+
+    let elf = fs::read(&firmware.path).context("Failed to read firmware image")?;
+    let table = match run_options.rtt {
+        true => defmt_decoder::Table::parse(&elf).context("Failed to parse defmt data")?,
+        false => None,
+    };
+
+    let mut core = session.core(0)?;
+    /* report crashes right away instead of waiting for the timeout */
+    if core
+        .enable_vector_catch(VectorCatchCondition::HardFault)
+        .is_err()
+    {
+        progress_callback("Unable to catch HardFaults of the target".to_string());
+    }
+
+    progress_callback(format!(
+        "Running {}/{} for up to {} s...",
+        firmware.name,
+        firmware.version,
+        run_options.timeout.as_secs()
+    ));
+    let start = Instant::now();
+    let mut console = ChannelDecoder::Text(Vec::new());
+    let mut rtt: Option<RttReader> = None;
+    let mut rtt_attempt: Option<Instant> = None;
+    let outcome = loop {
+        if let Some(command) = runner::check_status(core.status()?)? {
+            let exit = runner::handle_semihosting(&mut core, command, &mut console, line_callback)?;
+            if let Some(status) = exit {
+                break RunOutcome::Exited(status);
+            }
+            core.run()?;
+            continue;
+        }
+
+        /* the firmware sets up the RTT control block some time after reset */
+        if run_options.rtt
+            && rtt.is_none()
+            && rtt_attempt.is_none_or(|t| t.elapsed() > Duration::from_millis(500))
+        {
+            rtt_attempt = Some(Instant::now());
+            rtt = RttReader::attach(&mut core, &elf, table.as_ref(), Duration::ZERO).ok();
+        }
+        let received = match rtt.as_mut() {
+            Some(rtt) => rtt.poll(&mut core, line_callback)?,
+            None => false,
+        };
+
+        if start.elapsed() > run_options.timeout {
+            break RunOutcome::TimedOut;
+        }
+        if !received {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    /* pick up whatever the firmware logged right before exiting */
+    if run_options.rtt && rtt.is_none() {
+        rtt = RttReader::attach(&mut core, &elf, table.as_ref(), Duration::ZERO).ok();
+    }
+    if let Some(rtt) = rtt.as_mut() {
+        rtt.poll(&mut core, line_callback)?;
+    }
+
+    Ok(RunReport { flash, outcome })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET_YAML: &str = r#"
+name: Custom Series
+generated_from_pack: false
+variants:
+- name: CUSTOM_M4
+  cores:
+  - name: main
+    type: armv7em
+    core_access_options: !Arm
+      ap: !v1 0
+  memory_map:
+  - !Nvm
+    range:
+      start: 0x8000000
+      end: 0x8010000
+    cores:
+    - main
+  - !Ram
+    range:
+      start: 0x20000000
+      end: 0x20004000
+    cores:
+    - main
+  flash_algorithms: []
+flash_algorithms: []
+"#;
+
+    #[test]
+    fn test_persistent_attach_errors() {
+        assert!(!is_persistent_attach_error(&probe_rs::Error::Probe(
+            DebugProbeError::TargetNotFound
+        )));
+        assert!(!is_persistent_attach_error(&probe_rs::Error::Timeout));
+        assert!(is_persistent_attach_error(&probe_rs::Error::Probe(
+            DebugProbeError::Usb(std::io::Error::other("disconnected"))
+        )));
+    }
+
+    #[test]
+    fn test_register_target_description() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("target.yaml");
+        assert!(registry().unwrap().get_target_by_name("CUSTOM_M4").is_err());
+
+        fs::write(&path, TARGET_YAML).unwrap();
+        assert_eq!(register_target_description(&path).unwrap(), "Custom Series");
+        assert_eq!(
+            registry()
+                .unwrap()
+                .get_target_by_name("CUSTOM_M4")
+                .unwrap()
+                .name,
+            "CUSTOM_M4"
+        );
+
+        fs::write(&path, "name: [").unwrap();
+        assert!(register_target_description(&path).is_err());
+    }
+}
@@ -1,230 +1,1978 @@
 use anyhow::{self, Context};
-use chrono::DateTime;
+use chrono::{DateTime, Local, NaiveDate, NaiveTime};
 use clap::{self, Parser};
-use quick_flash::credentials::get_credentials_from_command_line;
+use probe_rs::probe::{DebugProbeInfo, Probe};
+use quick_flash::credentials::{
+    get_credentials_from_command_line, mask_secret, pick_storage_from_command_line, Credentials,
+    PartialCredentials, StorageType,
+};
 use quick_flash::credentials_manager::CredentialsManager;
-use quick_flash::storage::Storage;
-use quick_flash::{flash_firmware, get_probes, BaseDirs};
+use quick_flash::dump::DumpFormat;
+use quick_flash::history::{write_csv, FlashRecord, FlashResult, History, HistoryFilter};
+use quick_flash::probes::{
+    describe_probe, open_probe, probe_label, probe_selector_string, select_probe, select_probes,
+    ProbeAliases, ProbeSelector, ProbeSummary,
+};
+use quick_flash::provisioning::Provisioner;
+use quick_flash::runner::{RunOptions, RunOutcome, SEMIHOSTING_CHANNEL};
+use quick_flash::secrets::{read_passphrase, SecretStore};
+use quick_flash::storage::{Firmware, FirmwareMetadata, Storage};
+use quick_flash::versions::{
+    glob_to_regex, resolve_version, ListField, ListQuery, SortKey, LATEST,
+};
+use quick_flash::{
+    dump_memory, erase_flash, flash_firmware, flash_firmware_on_probes, get_probes, monitor_rtt,
+    parse_number, preview_option_bytes, run_firmware, wait_for_target, BaseDirs, EraseMode,
+    FlashOptions, FlashOutcome, FlashReport,
+};
+use regex::Regex;
+use serde::Serialize;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Flash centrally hosted firmware binaries with one command
 #[derive(clap::Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    arg_required_else_help = true,
+    mut_arg("firmware_name", |a| a
+        .required(false)
+        .required_unless_present_any(["list", "list_probes", "clear_cache"]))
+)]
 struct Args {
-    firmware_name: Option<String>,
-    firmware_version: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Lists available firmware names (if given no arguments) or versions of a specific firmware (if given FIRMWARE_NAME)
-    #[arg(long, short)]
-    list: bool,
+    /// Flashes the firmware, a shortcut for the flash command
+    #[command(flatten)]
+    flash: FlashArgs,
 
-    // TODO add '--probe VID:PID' or '--probe VID:PID:Serial'.
-    /// Select a specific probe in the list, accepts '--probe Serial'
-    #[arg(long)]
-    probe: Option<String>,
+    /// Name of the saved storage to use when more than one is saved
+    #[arg(long, global = true)]
+    storage: Option<String>,
 
-    /// Lists all available probes
-    #[arg(long)]
+    /// Use the storage credentials in this file instead of the saved ones, they take precedence over QUICK_FLASH_STORAGE_* environment variables
+    #[arg(long, value_name = "PATH", global = true)]
+    credentials: Option<PathBuf>,
+
+    /// Print listings and results as human readable 'text' or as 'json' lines, progress messages always go to stderr
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /* options replaced by subcommands, kept to point existing scripts to them */
+    #[arg(long, short, hide = true)]
+    list: bool,
+
+    #[arg(long, hide = true)]
     list_probes: bool,
 
     /// Deletes the cache directory prior to running the rest of the program
-    #[arg(long)]
+    #[arg(long, hide = true)]
     clear_cache: bool,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Flashes a firmware version onto the boards connected to the selected probes
+    Flash(FlashArgs),
+    /// Lists available firmware names, or versions of a specific firmware if given FIRMWARE_NAME
+    List(ListArgs),
+    /// Lists all available probes
+    Probes,
+    /// Shows the manifest of a firmware version
+    Info(InfoArgs),
+    /// Manages the storage credentials
+    #[command(subcommand)]
+    Storage(StorageCommand),
+    /// Manages the downloaded firmware cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Reads target memory back into a .bin, .hex or .elf file, all flash regions are read unless a range is given
+    Dump(DumpArgs),
+    /// Erases the target flash, the whole chip is erased unless a range is given
+    Erase(EraseArgs),
+    /// Gives the probe selected by --probe a persistent alias usable in place of its serial number
+    Alias(AliasArgs),
+    /// Lists previously flashed boards, filtered by the given criteria
+    History(HistoryArgs),
+    /// Flashes and runs a test firmware, exiting with the status it reports through semihosting
+    Run(RunArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct FlashArgs {
+    /// Name of the firmware
+    #[arg(index = 1, required = true)]
+    firmware_name: Option<String>,
 
-    /* /// Deletes the credentials file prior to running the rest of the program
+    /// Version to flash, also accepts 'latest', 'latest-semver', a semver requirement like '^1.4' or a git hash prefix
+    #[arg(index = 2)]
+    firmware_version: Option<String>,
+
+    /// Only consider versions on this release channel (or with this tag), flashes the newest one if no version is given
     #[arg(long)]
-    clear_credentials: bool, */
+    channel: Option<String>,
+
+    /// Production mode, keeps flashing boards one after another as they are connected to the probe
+    #[arg(long = "loop")]
+    loop_mode: bool,
+
+    /// Stream the RTT channels of the target after flashing, defmt logs are decoded using the firmware
+    #[arg(long, conflicts_with = "loop_mode")]
+    monitor: bool,
+
+    /// Also write the monitored log to a file, each line prefixed with a timestamp
+    #[arg(long, value_name = "PATH", requires = "monitor")]
+    log_file: Option<PathBuf>,
+
+    /// Only print the option byte writes declared in the firmware manifest, without flashing
+    #[arg(long, conflicts_with_all = ["loop_mode", "monitor"])]
+    option_bytes_dry_run: bool,
+
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    #[command(flatten)]
+    program: ProgramArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConnectArgs {
+    /// Select a specific probe in the list, accepts '--probe VID:PID', '--probe VID:PID:Serial', '--probe Serial' or '--probe Alias'.
+    /// When flashing, a comma separated list of probes or '--probe all' flashes all of them in parallel
+    #[arg(long, value_delimiter = ',')]
+    probe: Vec<ProbeSelector>,
+
     /// Use this flag to assert the nreset & ntrst pins during attaching the probe to the chip
     #[arg(long, short('r'))]
     connect_under_reset: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ProgramArgs {
+    /// Compare the target flash with the firmware first and skip programming if it is already up to date
+    #[arg(long)]
+    if_changed: bool,
+
+    /// CSV file with a header row supplying the values of "csv" provisioning patch points, one row per device
+    #[arg(long, value_name = "PATH")]
+    provision_csv: Option<PathBuf>,
+
+    /// Flash even if the connected chip does not match the chip in the firmware manifest
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    /// List the versions of this firmware instead of the firmware names
+    firmware_name: Option<String>,
+
+    /// Only list versions on this release channel (or with this tag)
+    #[arg(long, requires = "firmware_name")]
+    channel: Option<String>,
 
     /// Show dates of last modification for entries in the list
     #[arg(long)]
     dates: bool,
+
+    /// Show the release channels and tags of the listed versions
+    #[arg(long, requires = "firmware_name")]
+    tags: bool,
+
+    /// Sort the list by 'date', 'semver' or 'name'
+    #[arg(long)]
+    sort: Option<SortKey>,
+
+    /// Reverse the order of the list
+    #[arg(long)]
+    reverse: bool,
+
+    /// Only list the first N entries
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Only list entries matching a glob, e.g. 'v2.*'
+    #[arg(long, value_parser = glob_to_regex, conflicts_with = "filter_regex")]
+    filter: Option<Regex>,
+
+    /// Only list entries matching a regular expression
+    #[arg(long)]
+    filter_regex: Option<Regex>,
+
+    /// Only list entries modified on or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    since: Option<NaiveDate>,
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    /// Name of the firmware
+    firmware_name: String,
 
-    if args.list_probes {
-        let probes = get_probes()?;
-        println!(
-            "VID:PID:Serial (name) listing of {} available debug probe{}:",
-            probes.len(),
-            if probes.len().eq(&1) { "" } else { "s" }
-        );
-        for probe in probes {
-            println!(
-                "  - {:04X}:{:04X}:{} ({})",
-                probe.vendor_id,
-                probe.product_id,
-                probe.serial_number.unwrap_or_default(),
-                probe.identifier
-            );
+    /// Version of the firmware, resolved like the version to flash
+    firmware_version: Option<String>,
+
+    /// Pick the version from this release channel (or tag)
+    #[arg(long)]
+    channel: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum StorageCommand {
+    /// Adds new storage credentials, the ones not given as options are asked for interactively
+    Add(StorageAddArgs),
+    /// Removes the credentials of a storage
+    Remove {
+        /// Name of the storage
+        name: String,
+    },
+    /// Lists the saved storages, secret keys are masked
+    List,
+    /// Renames a saved storage
+    Rename {
+        /// Current name of the storage
+        name: String,
+        /// New name of the storage
+        new_name: String,
+    },
+    /// Checks that the storage is reachable with the saved credentials and lists its firmware
+    Test {
+        /// Name of the storage, all storages are tested if not given
+        name: Option<String>,
+    },
+    /// Prints a token with the credentials of a storage for sharing them
+    Export {
+        /// Name of the storage
+        name: String,
+        /// Write the token to this file instead of printing it
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+        /// Encrypt the token with a passphrase
+        #[arg(long)]
+        protect: bool,
+    },
+    /// Adds a storage from a token made by the export command
+    Import(StorageImportArgs),
+    /// Moves saved secret keys to another secret store
+    Migrate {
+        /// Secret store to move to: keyring, encrypted or plaintext
+        store: SecretStore,
+        /// Name of the storage, all storages are moved if not given
+        name: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct StorageImportArgs {
+    /// The token, or a file containing it
+    token: String,
+
+    /// Name of the storage, defaults to the name it was exported with
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Import the credentials without checking that the bucket can be read with them
+    #[arg(long)]
+    skip_check: bool,
+
+    /// Where to keep the secret key: plaintext, keyring (the OS keyring) or encrypted (with a passphrase)
+    #[arg(long, default_value = "plaintext")]
+    secret_store: SecretStore,
+}
+
+#[derive(clap::Args, Debug)]
+struct StorageAddArgs {
+    /// Name of the storage for future reference, defaults to the bucket name
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Name of the bucket
+    #[arg(long)]
+    bucket: Option<String>,
+
+    /// Account ID of the bucket owner
+    #[arg(long)]
+    account_id: Option<String>,
+
+    /// Access key of the API token
+    #[arg(long)]
+    access_key: Option<String>,
+
+    /// Secret key of the API token
+    #[arg(long)]
+    secret_key: Option<String>,
+
+    /// Save the credentials without checking that the bucket can be read with them
+    #[arg(long)]
+    skip_check: bool,
+
+    /// Where to keep the secret key: plaintext, keyring (the OS keyring) or encrypted (with a passphrase)
+    #[arg(long, default_value = "plaintext")]
+    secret_store: SecretStore,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum CacheCommand {
+    /// Lists the downloaded firmware versions
+    List,
+    /// Deletes the downloaded firmware, only of FIRMWARE_NAME if given
+    Clear {
+        /// Only delete the versions of this firmware
+        firmware_name: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// Name of the test firmware
+    firmware_name: String,
+
+    /// Version of the test firmware, resolved like the version to flash
+    firmware_version: String,
+
+    /// Seconds to wait for the firmware to exit, the exit code is 124 when it does not
+    #[arg(long, default_value_t = 60)]
+    timeout: u64,
+
+    /// Also stream the RTT channels of the firmware
+    #[arg(long)]
+    rtt: bool,
+
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    #[command(flatten)]
+    program: ProgramArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// Only show boards with a matching target chip
+    #[arg(long)]
+    board: Option<String>,
+
+    /// Only show a firmware, given as 'name' or 'name/version'
+    #[arg(long)]
+    firmware: Option<String>,
+
+    /// Only show boards flashed with this probe, given as (part of) its 'VID:PID:Serial' selector or as its alias
+    #[arg(long)]
+    probe: Option<String>,
+
+    /// Only show boards flashed on or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    since: Option<NaiveDate>,
+
+    /// Only show boards flashed on or before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    until: Option<NaiveDate>,
+
+    /// Export the matching records into a CSV file instead of listing them
+    #[arg(long)]
+    csv: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct AliasArgs {
+    /// Alias of the probe, e.g. 'rig-3-left'
+    alias: String,
+
+    /// Remove the alias instead
+    #[arg(long)]
+    remove: bool,
+
+    /// Probe to give the alias, accepts the same forms as when flashing and is needed when several probes are connected
+    #[arg(long, conflicts_with = "remove")]
+    probe: Option<ProbeSelector>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DumpArgs {
+    /// Output file, its extension selects the format (.bin, .hex or .elf)
+    #[arg(value_name = "OUTPUT")]
+    file: PathBuf,
+
+    #[command(flatten)]
+    target: TargetArgs,
+
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    #[command(flatten)]
+    range: RangeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct EraseArgs {
+    #[command(flatten)]
+    target: TargetArgs,
+
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    #[command(flatten)]
+    range: RangeArgs,
+
+    /// Unlock a read-protected target by erasing all of its memory, this may also reset security settings
+    #[arg(long, conflicts_with = "address")]
+    unlock: bool,
+
+    /// Do not ask for confirmation
+    #[arg(long, short)]
+    yes: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct RangeArgs {
+    /// Start address of the memory range
+    #[arg(long, value_parser = parse_number, requires = "length")]
+    address: Option<u64>,
+
+    /// Length of the memory range in bytes
+    #[arg(long, value_parser = parse_number, requires = "address")]
+    length: Option<u64>,
+}
+
+impl RangeArgs {
+    fn range(&self) -> anyhow::Result<Option<Range<u64>>> {
+        let (Some(address), Some(length)) = (self.address, self.length) else {
+            return Ok(None);
+        };
+        let end = address.checked_add(length).context(format!(
+            "Memory range of {:#x} bytes at {:#x} does not fit in the address space",
+            length, address
+        ))?;
+        Ok(Some(address..end))
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+struct TargetArgs {
+    /// Target chip name as listed by probe-rs
+    #[arg(long)]
+    chip: Option<String>,
+
+    /// Take the target chip from the manifest of this firmware version
+    #[arg(long, num_args = 2, value_names = ["FIRMWARE_NAME", "FIRMWARE_VERSION"])]
+    firmware: Option<Vec<String>>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/* JSON output schema, see the README before changing it */
+
+#[derive(Serialize)]
+struct FirmwareListOutput<'a> {
+    firmwares: &'a [FirmwareMetadata],
+}
+
+#[derive(Serialize)]
+struct VersionListOutput<'a> {
+    name: &'a str,
+    channel: Option<&'a str>,
+    versions: &'a [FirmwareMetadata],
+}
+
+#[derive(Serialize)]
+struct ProbeListOutput {
+    probes: Vec<ProbeSummary>,
+}
+
+#[derive(Serialize)]
+struct FlashOutput {
+    results: Vec<FlashRecord>,
+}
+
+#[derive(Serialize)]
+struct RunOutput {
+    results: Vec<FlashRecord>,
+    exit_status: Option<i32>,
+    timed_out: bool,
+}
+
+#[derive(Serialize)]
+struct InfoOutput<'a> {
+    metadata: &'a FirmwareMetadata,
+    firmware: &'a Firmware,
+}
+
+#[derive(Serialize)]
+struct TokenOutput<'a> {
+    name: &'a str,
+    token: String,
+}
+
+#[derive(Serialize)]
+struct StorageListOutput {
+    storages: Vec<StorageSummary>,
+}
+
+#[derive(Serialize)]
+struct StorageSummary {
+    name: String,
+    storage_type: StorageType,
+    bucket: String,
+    account_id: String,
+    endpoint: Option<String>,
+    access_key: String,
+    /// Masked, only given for secret keys kept in plaintext
+    secret_key: Option<String>,
+    secret_store: SecretStore,
+}
+
+impl StorageSummary {
+    /// Summarizes credentials as stored, without reading their secret key.
+    fn new(creds: &Credentials) -> Self {
+        StorageSummary {
+            name: creds.user_storage_name.clone(),
+            storage_type: creds.storage_type.clone(),
+            bucket: creds.storage_name.clone(),
+            account_id: creds.storage_account_id.clone(),
+            endpoint: creds.storage_endpoint.clone(),
+            access_key: creds.storage_access_key.clone(),
+            secret_key: match creds.secret_store {
+                SecretStore::Plaintext => Some(mask_secret(&creds.storage_secret_key)),
+                _ => None,
+            },
+            secret_store: creds.secret_store,
         }
-        exit(0);
     }
+}
 
-    let base_dirs = BaseDirs::new()?;
+#[derive(Serialize)]
+struct CacheListOutput<'a> {
+    cached: &'a [CachedFirmware],
+}
 
-    if args.clear_cache {
-        eprintln!("Clearing cache directory...");
-        base_dirs
-            .clear_firmware_cache()
-            .context("Failed to clear firmware cache directory")?;
+#[derive(Serialize)]
+struct CachedFirmware {
+    name: String,
+    version: String,
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct HistoryOutput<'a> {
+    records: &'a [FlashRecord],
+}
+
+#[derive(Serialize)]
+struct LineOutput<'a> {
+    channel: &'a str,
+    line: &'a str,
+}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: String,
+    causes: Vec<String>,
+}
+
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Error: Failed to serialize the output ({})", e),
     }
+}
 
-    /* if args.clear_credentials {
-        eprintln!("Clearing credentials...");
-        if creds_path.exists() {
-            fs::remove_file(&creds_path).context("Failed to remove credentials file")?;
-        }
-    } */
+fn parse_date(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| e.to_string())
+}
+
+fn local_timestamp(date: NaiveDate) -> anyhow::Result<i64> {
+    date.and_time(NaiveTime::MIN)
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|t| t.timestamp())
+        .context("Invalid local date")
+}
+
+/// Credentials given with --credentials or in environment variables, they
+/// are used as they are and never saved.
+fn external_credentials(args: &Args) -> anyhow::Result<Option<Credentials>> {
+    let Some(path) = &args.credentials else {
+        return Credentials::from_env().context("Failed to read credentials from the environment");
+    };
+    let creds = Credentials::read_from_path(path)?;
+    if creds.secret_store != SecretStore::Plaintext || creds.storage_secret_key.is_empty() {
+        anyhow::bail!(
+            "Credentials file {} does not contain the secret key",
+            path.display()
+        );
+    }
+    Ok(Some(creds))
+}
+
+fn connect_storage(args: &Args, base_dirs: &BaseDirs) -> anyhow::Result<Storage> {
+    if let Some(creds) = external_credentials(args)? {
+        eprintln!("Connecting to \"{}\" storage...", creds.user_storage_name);
+        return Storage::new(&creds).context("Failed to init storage client");
+    }
 
-    let creds_manager = CredentialsManager::new(base_dirs.creds_dir);
+    let creds_manager = CredentialsManager::new(base_dirs.creds_dir.clone());
     let mut all_creds = creds_manager
-        .get_all()
+        .get_all_stored()
         .context("Failed to load saved credentials")?;
 
     if all_creds.is_empty() {
-        let creds = get_credentials_from_command_line()
-            .context("Failed to read credentials from the command line")?;
+        let creds = enter_credentials(PartialCredentials::default(), true)?;
         creds_manager
             .add(creds)
             .context("Failed to save new credentials")?;
         eprintln!("Credentials saved successfully");
-        all_creds = creds_manager.get_all()?;
+        all_creds = creds_manager.get_all_stored()?;
     }
 
-    if all_creds.len() > 1 {
-        anyhow::bail!("Multiple credentials management is not supported in this version");
+    let creds = match (&args.storage, &all_creds[..]) {
+        (Some(name), _) => all_creds
+            .iter()
+            .find(|c| c.user_storage_name == *name)
+            .context(format!(
+            "Storage \"{}\" not found (you can use the storage list command to list all storages)",
+            name
+        ))?,
+        (None, [creds]) => creds,
+        (None, _) if std::io::stdin().is_terminal() => pick_storage_from_command_line(&all_creds)?,
+        (None, _) => anyhow::bail!(
+            "Multiple storages are saved, please select one using --storage: {}",
+            all_creds
+                .iter()
+                .map(|c| c.user_storage_name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ),
+    };
+
+    let creds = creds_manager
+        .get(&creds.user_storage_name)
+        .context("Failed to load the secret key")?;
+    eprintln!("Connecting to \"{}\" storage...", creds.user_storage_name);
+    Storage::new(&creds).context("Failed to init storage client")
+}
+
+fn check_credentials(creds: &Credentials) -> anyhow::Result<()> {
+    eprintln!("Checking access to \"{}\" bucket...", creds.storage_name);
+    Storage::new(creds).and_then(|s| s.check_access())
+}
+
+/// Reads new credentials and checks them against the storage, on failure the
+/// user can enter them again or save them anyway.
+fn enter_credentials(mut known: PartialCredentials, check: bool) -> anyhow::Result<Credentials> {
+    loop {
+        let creds = get_credentials_from_command_line(known)
+            .context("Failed to read credentials from the command line")?;
+        if !check {
+            return Ok(creds);
+        }
+
+        let error = match check_credentials(&creds) {
+            Ok(()) => return Ok(creds),
+            Err(e) => e,
+        };
+        if !std::io::stdin().is_terminal() {
+            return Err(error)
+                .context("Credentials check failed (use --skip-check to save anyway)");
+        }
+
+        eprintln!("Credentials check failed: {:#}", error);
+        eprint!("Enter the credentials again (r), save them anyway (s) or abort (a)? [r/s/A]: ");
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        match answer.trim() {
+            "r" | "R" => known = PartialCredentials::default(),
+            "s" | "S" => return Ok(creds),
+            _ => anyhow::bail!("Adding credentials aborted"),
+        }
     }
+}
 
-    let creds = all_creds.first().unwrap();
+fn resolve_chip(args: &Args, target: &TargetArgs, base_dirs: &BaseDirs) -> anyhow::Result<String> {
+    match (&target.chip, &target.firmware) {
+        (Some(chip), _) => Ok(chip.clone()),
+        (None, Some(firmware)) => {
+            let storage = connect_storage(args, base_dirs)?;
+            let version = storage.resolve_firmware_version(&firmware[0], &firmware[1])?;
+            print_resolved_version(&firmware[1], &version);
+            let firmware = storage
+                .download_firmware(&firmware[0], &version, &base_dirs.firmware_cache_dir)
+                .context("Failed to download firmware")?;
+            Ok(firmware.chip)
+        }
+        (None, None) => anyhow::bail!("Please specify the target chip"),
+    }
+}
 
-    eprintln!("Connecting to \"{}\" storage...", creds.user_storage_name);
-    let storage = Storage::new(creds).context("Failed to init storage client")?;
+fn list_entry(label: &str, firmware: &FirmwareMetadata, dates: bool) -> anyhow::Result<String> {
+    let mut line = format!("  - {}", label);
+    if dates {
+        line += &format!(
+            " ({})",
+            DateTime::from_timestamp(firmware.last_modified, 0)
+                .ok_or(anyhow::anyhow!("not a timestamp"))?
+        );
+    }
+    if !firmware.tags.is_empty() {
+        line += &format!(" [{}]", firmware.tags.join(", "));
+    }
+    Ok(line)
+}
 
-    let (firmwares, firmwares_modified) = storage
-        .list_firmwares()
-        .context("Failed to fetch firmware names from the Bucket")?
-        .iter()
-        .map(|f| (f.name.clone(), f.last_modified))
-        .collect::<(Vec<String>, Vec<i64>)>();
+fn print_resolved_version(spec: &str, version: &str) {
+    if spec != version {
+        eprintln!("Resolved version \"{}\" to {}", spec, version);
+    }
+}
 
-    if firmwares.is_empty() {
-        anyhow::bail!("No firmware found in the Bucket");
+fn single_probe_selector(selectors: &[ProbeSelector]) -> anyhow::Result<Option<&ProbeSelector>> {
+    match selectors {
+        [] => Ok(None),
+        [selector] => Ok(Some(selector)),
+        _ => anyhow::bail!("Only a single probe can be selected for this command"),
     }
+}
+
+fn open_selected_probe(selectors: &[ProbeSelector], base_dirs: &BaseDirs) -> anyhow::Result<Probe> {
+    let aliases = ProbeAliases::read_from_path(&base_dirs.probe_aliases_path)?;
+    open_probe(single_probe_selector(selectors)?, &aliases)
+}
+
+fn dump(args: &Args, dump_args: &DumpArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    DumpFormat::from_path(&dump_args.file)?;
+    let range = dump_args.range.range()?;
+    let chip = resolve_chip(args, &dump_args.target, base_dirs)?;
+
+    let probe = open_selected_probe(&dump_args.connect.probe, base_dirs)?;
+    let dump = dump_memory(
+        probe,
+        &chip,
+        dump_args.connect.connect_under_reset,
+        range,
+        &|s| {
+            eprintln!("{}", s);
+        },
+    )?;
+
+    for path in dump
+        .write_to_path(&dump_args.file)
+        .context("Failed to write dump file")?
+    {
+        eprintln!("Saved {}", path.display());
+    }
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    eprint!("{} [y/N]: ", prompt);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn erase(args: &Args, erase_args: &EraseArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let chip = resolve_chip(args, &erase_args.target, base_dirs)?;
+    let mode = match (erase_args.range.range()?, erase_args.unlock) {
+        (Some(range), _) => EraseMode::Range(range),
+        (None, false) => EraseMode::Chip,
+        (None, true) => EraseMode::Unlock,
+    };
+
+    let prompt = match mode {
+        EraseMode::Range(ref range) => format!(
+            "Erase {:#010x}..{:#010x} of the {} target?",
+            range.start, range.end, chip
+        ),
+        EraseMode::Chip => format!("Erase all flash of the {} target?", chip),
+        EraseMode::Unlock => format!(
+            "Unlock and erase all memory of the {} target? This may irreversibly reset its protection settings",
+            chip
+        ),
+    };
+    if !erase_args.yes && !confirm(&prompt)? {
+        anyhow::bail!("Erase aborted");
+    }
+
+    let probe = open_selected_probe(&erase_args.connect.probe, base_dirs)?;
+    erase_flash(
+        probe,
+        &chip,
+        erase_args.connect.connect_under_reset,
+        mode,
+        &|s| {
+            eprintln!("{}", s);
+        },
+    )
+}
+
+fn alias(alias_args: &AliasArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let mut aliases = ProbeAliases::read_from_path(&base_dirs.probe_aliases_path)?;
+    if alias_args.remove {
+        aliases.remove(&alias_args.alias)?;
+        eprintln!("Removed probe alias \"{}\"", alias_args.alias);
+    } else {
+        let probes = get_probes()?;
+        let probe = select_probe(&probes, alias_args.probe.as_ref(), &aliases)?;
+        let serial_number = probe
+            .serial_number
+            .as_ref()
+            .filter(|s| !s.is_empty())
+            .context("Only probes with a serial number can be given an alias")?;
+        aliases.set(&alias_args.alias, serial_number)?;
+        eprintln!(
+            "Probe {} is now known as \"{}\"",
+            describe_probe(probe, &ProbeAliases::default()),
+            alias_args.alias
+        );
+    }
+    aliases
+        .write_to_path(&base_dirs.probe_aliases_path)
+        .context("Failed to save probe aliases")
+}
+
+fn history(args: &Args, history_args: &HistoryArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let filter = HistoryFilter {
+        board: history_args.board.clone(),
+        probe: history_args.probe.clone(),
+        firmware: history_args.firmware.clone(),
+        since: history_args.since.map(local_timestamp).transpose()?,
+        until: history_args
+            .until
+            .and_then(|d| d.succ_opt())
+            .map(local_timestamp)
+            .transpose()?,
+    };
+    let records = History::new(base_dirs.history_path.clone()).read_filtered(&filter)?;
+
+    if let Some(ref path) = history_args.csv {
+        write_csv(&records, path)?;
+        eprintln!("Exported {} records to {}", records.len(), path.display());
+        return Ok(());
+    }
+
+    if args.output == OutputFormat::Json {
+        print_json(&HistoryOutput { records: &records });
+        return Ok(());
+    }
+
+    println!(
+        "Listing {} flashed board{}:",
+        records.len(),
+        if records.len().eq(&1) { "" } else { "s" }
+    );
+    for r in records {
+        println!(
+            "  - {} {}/{} on {}{} via {} by {}: {}",
+            DateTime::from_timestamp(r.timestamp, 0)
+                .ok_or(anyhow::anyhow!("not a timestamp"))?
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            r.firmware_name,
+            r.firmware_version,
+            r.chip,
+            r.device_id
+                .map_or(String::new(), |id| format!(" (ID {})", id)),
+            r.probe_alias.unwrap_or(r.probe),
+            r.user,
+            match r.result {
+                FlashResult::Programmed => "PASS".to_string(),
+                FlashResult::Unchanged => "PASS (unchanged)".to_string(),
+                FlashResult::Failed => format!("FAIL ({})", r.error.unwrap_or_default()),
+            }
+        );
+    }
+    Ok(())
+}
+
+fn print_result_banner(pass: bool) {
+    let (text, color) = match pass {
+        true => ("PASS", "32"),
+        false => ("FAIL", "31"),
+    };
+    let banner = format!("{0}  {1}  {0}", "#".repeat(20), text);
+    if std::io::stdout().is_terminal() {
+        println!("\x1b[1;{}m{}\x1b[0m", color, banner);
+    } else {
+        println!("{}", banner);
+    }
+}
+
+fn flash_options(
+    connect_args: &ConnectArgs,
+    program_args: &ProgramArgs,
+    firmware: &Firmware,
+    base_dirs: &BaseDirs,
+) -> anyhow::Result<FlashOptions> {
+    let provisioner = match firmware.provisioning.is_empty() {
+        true => None,
+        false => Some(Arc::new(Provisioner::new(
+            base_dirs.provisioning_ledger_path.clone(),
+            program_args.provision_csv.as_deref(),
+        )?)),
+    };
+    Ok(FlashOptions {
+        connect_under_reset: connect_args.connect_under_reset,
+        if_changed: program_args.if_changed,
+        provisioner,
+        force: program_args.force,
+    })
+}
+
+/// Exit code for a status reported by the firmware, only 0..=255 survive as
+/// process exit codes, any other status is a failure and maps to 1.
+fn exit_code(status: i32) -> i32 {
+    match status {
+        0..=255 => status,
+        _ => 1,
+    }
+}
+
+fn run(args: &Args, run_args: &RunArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let storage = connect_storage(args, base_dirs)?;
+    let version =
+        storage.resolve_firmware_version(&run_args.firmware_name, &run_args.firmware_version)?;
+    print_resolved_version(&run_args.firmware_version, &version);
+    let firmware = storage
+        .download_firmware(
+            &run_args.firmware_name,
+            &version,
+            &base_dirs.firmware_cache_dir,
+        )
+        .context("Failed to download firmware")?;
+
+    let probes = get_probes()?;
+    let aliases = ProbeAliases::read_from_path(&base_dirs.probe_aliases_path)?;
+    let probe = select_probe(
+        &probes,
+        single_probe_selector(&run_args.connect.probe)?,
+        &aliases,
+    )?;
+    let flash_options = flash_options(&run_args.connect, &run_args.program, &firmware, base_dirs)?;
+    let run_options = RunOptions {
+        timeout: Duration::from_secs(run_args.timeout),
+        rtt: run_args.rtt,
+    };
+
+    let result = probe.open().context("Failed to open probe").and_then(|p| {
+        run_firmware(
+            p,
+            firmware.clone(),
+            &flash_options,
+            &run_options,
+            &|s| eprintln!("{}", s),
+            &mut |channel, line| match (args.output, channel) {
+                (OutputFormat::Json, _) => print_json(&LineOutput {
+                    channel,
+                    line: &line,
+                }),
+                (OutputFormat::Text, SEMIHOSTING_CHANNEL) => println!("{}", line),
+                (OutputFormat::Text, _) => println!("[{}] {}", channel, line),
+            },
+        )
+    });
+
+    let flash_log = FlashLog {
+        history: History::new(base_dirs.history_path.clone()),
+        storage: storage.name(),
+        aliases: &aliases,
+    };
+    let record = flash_log.record(
+        &firmware,
+        probe,
+        &result
+            .as_ref()
+            .map(|r| r.flash.clone())
+            .map_err(|e| anyhow::anyhow!("{:#}", e)),
+    );
+    if args.output == OutputFormat::Json {
+        let outcome = result.as_ref().ok().map(|r| r.outcome);
+        print_json(&RunOutput {
+            results: vec![record],
+            exit_status: match outcome {
+                Some(RunOutcome::Exited(status)) => Some(status),
+                _ => None,
+            },
+            timed_out: outcome == Some(RunOutcome::TimedOut),
+        });
+        /* the error is part of the record, it gets no document of its own */
+        if result.is_err() {
+            exit(1);
+        }
+    }
+
+    match result?.outcome {
+        RunOutcome::Exited(status) => {
+            eprintln!("Firmware exited with status {}", status);
+            exit(exit_code(status));
+        }
+        RunOutcome::TimedOut => {
+            eprintln!("Firmware did not exit within {} seconds", run_args.timeout);
+            exit(124);
+        }
+    }
+}
+
+/// Records flashing results into the flash history.
+struct FlashLog<'a> {
+    history: History,
+    storage: &'a str,
+    aliases: &'a ProbeAliases,
+}
+
+impl FlashLog<'_> {
+    fn record(
+        &self,
+        firmware: &Firmware,
+        probe: &DebugProbeInfo,
+        result: &anyhow::Result<FlashReport>,
+    ) -> FlashRecord {
+        let record = FlashRecord::new(
+            self.storage,
+            firmware,
+            probe_selector_string(probe),
+            self.aliases.alias_of(probe).map(str::to_owned),
+            result,
+        );
+        if let Err(e) = self.history.append(&record) {
+            eprintln!("Warning: {:#}", e);
+        }
+        record
+    }
+}
+
+fn monitor(
+    probe: &DebugProbeInfo,
+    firmware: &Firmware,
+    log_file: Option<&Path>,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut log_file = match log_file {
+        Some(path) => Some(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .context(format!("Failed to open log file {}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let probe = probe.open().context("Failed to open probe")?;
+    monitor_rtt(
+        probe,
+        firmware,
+        &|s| eprintln!("{}", s),
+        &mut |channel, line| {
+            match output {
+                OutputFormat::Text => println!("{}", line),
+                OutputFormat::Json => print_json(&LineOutput {
+                    channel,
+                    line: &line,
+                }),
+            }
+            if let Some(file) = log_file.as_mut() {
+                let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+                if let Err(e) = writeln!(file, "{} [{}] {}", timestamp, channel, line) {
+                    eprintln!(
+                        "Warning: Failed to write to the log file ({}), disabling it",
+                        e
+                    );
+                    log_file = None;
+                }
+            }
+        },
+    )
+}
+
+fn flash_loop(
+    probe: &DebugProbeInfo,
+    firmware: &Firmware,
+    flash_options: &FlashOptions,
+    flash_log: &FlashLog,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let (mut passed, mut failed) = (0, 0);
+    loop {
+        eprintln!("Waiting for a board to be connected...");
+        wait_for_target(probe, &firmware.chip, true)?;
+
+        let result = probe.open().context("Failed to open probe").and_then(|p| {
+            flash_firmware(p, firmware.clone(), flash_options, &|s| {
+                eprintln!("{}", s);
+            })
+        });
+        let record = flash_log.record(firmware, probe, &result);
+        match result {
+            Ok(_) => passed += 1,
+            Err(ref e) => {
+                failed += 1;
+                eprintln!("Error: {:#}", e);
+            }
+        }
+
+        match output {
+            OutputFormat::Json => print_json(&FlashOutput {
+                results: vec![record],
+            }),
+            OutputFormat::Text => {
+                print_result_banner(result.is_ok());
+                println!(
+                    "{} board #{}{} {}/{} {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    passed + failed,
+                    result
+                        .as_ref()
+                        .ok()
+                        .and_then(|r| r.device_id.as_ref())
+                        .map_or(String::new(), |id| format!(" (ID {})", id)),
+                    firmware.name,
+                    firmware.version,
+                    if result.is_ok() { "PASS" } else { "FAIL" }
+                );
+            }
+        }
+        eprintln!(
+            "Boards flashed this session: {} passed, {} failed",
+            passed, failed
+        );
+
+        eprintln!("Waiting for the board to be removed...");
+        wait_for_target(probe, &firmware.chip, false)?;
+    }
+}
+
+/// Points uses of the options replaced by subcommands to the subcommands,
+/// these are usage errors like the ones reported by clap.
+fn replaced_option_hint(list: bool, list_probes: bool) -> Option<&'static str> {
+    if list {
+        return Some(
+            "The --list option was replaced by the list command, e.g. 'quick-flash list blinky'",
+        );
+    }
+    if list_probes {
+        return Some("The --list-probes option was replaced by the probes command");
+    }
+    None
+}
+
+/// Output format asked for on the command line, also when it can not be
+/// parsed as a whole.
+fn requested_output(argv: &[String]) -> OutputFormat {
+    let json = argv
+        .windows(2)
+        .any(|w| w[0] == "--output" && w[1] == "json")
+        || argv.iter().any(|a| a == "--output=json");
+    match json {
+        true => OutputFormat::Json,
+        false => OutputFormat::Text,
+    }
+}
+
+/// Reports a usage error and exits with the exit code clap uses for them.
+fn usage_error(message: &str, output: OutputFormat) -> ! {
+    match output {
+        OutputFormat::Json => print_json(&ErrorOutput {
+            error: message.to_string(),
+            causes: vec![],
+        }),
+        OutputFormat::Text => eprintln!("error: {}", message),
+    }
+    exit(2);
+}
+
+fn main() -> anyhow::Result<()> {
+    let argv = std::env::args().collect::<Vec<String>>();
+    let args = match Args::try_parse_from(&argv) {
+        Ok(args) => args,
+        Err(e) if e.use_stderr() => {
+            let output = requested_output(&argv);
+            /* options of the commands that replaced them, e.g. '--list --dates', are
+             * not accepted next to the replaced options, but still deserve the hint */
+            let used = |option: &str| argv.iter().take_while(|a| *a != "--").any(|a| a == option);
+            if let Some(hint) =
+                replaced_option_hint(used("--list") || used("-l"), used("--list-probes"))
+            {
+                usage_error(hint, output);
+            }
+            if output == OutputFormat::Text {
+                e.exit();
+            }
+            /* the first paragraph holds the error, the rest are usage tips */
+            let message = e.render().to_string();
+            let message = message
+                .split("\n\n")
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches("error: ")
+                .lines()
+                .map(str::trim)
+                .collect::<Vec<&str>>()
+                .join(" ");
+            usage_error(&message, output)
+        }
+        Err(e) => e.exit(),
+    };
+    let output = args.output;
+    if let Some(hint) = replaced_option_hint(args.list, args.list_probes) {
+        usage_error(hint, output);
+    }
+
+    match (execute(args), output) {
+        (Err(e), OutputFormat::Json) => {
+            print_json(&ErrorOutput {
+                error: e.to_string(),
+                causes: e.chain().skip(1).map(|c| c.to_string()).collect(),
+            });
+            exit(1);
+        }
+        (result, _) => result,
+    }
+}
+
+fn execute(args: Args) -> anyhow::Result<()> {
+    let base_dirs = BaseDirs::new()?;
+
+    if args.clear_cache {
+        clear_cache(&base_dirs, None)?;
+        if args.command.is_none() && args.flash.firmware_name.is_none() {
+            eprintln!("The --clear-cache option was replaced by 'quick-flash cache clear'");
+            return Ok(());
+        }
+    }
+
+    match args.command {
+        Some(Command::Flash(ref flash_args)) => flash(&args, flash_args, &base_dirs),
+        Some(Command::List(ref list_args)) => list(&args, list_args, &base_dirs),
+        Some(Command::Probes) => probes(&args, &base_dirs),
+        Some(Command::Info(ref info_args)) => info(&args, info_args, &base_dirs),
+        Some(Command::Storage(ref storage_command)) => storage(&args, storage_command, &base_dirs),
+        Some(Command::Cache(ref cache_command)) => cache(&args, cache_command, &base_dirs),
+        Some(Command::Dump(ref dump_args)) => dump(&args, dump_args, &base_dirs),
+        Some(Command::Erase(ref erase_args)) => erase(&args, erase_args, &base_dirs),
+        Some(Command::Alias(ref alias_args)) => alias(alias_args, &base_dirs),
+        Some(Command::History(ref history_args)) => history(&args, history_args, &base_dirs),
+        Some(Command::Run(ref run_args)) => run(&args, run_args, &base_dirs),
+        None => flash(&args, &args.flash, &base_dirs),
+    }
+}
+
+fn probes(args: &Args, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let probes = get_probes()?;
+    let aliases = ProbeAliases::read_from_path(&base_dirs.probe_aliases_path)?;
+    if args.output == OutputFormat::Json {
+        print_json(&ProbeListOutput {
+            probes: probes
+                .iter()
+                .map(|p| ProbeSummary::new(p, &aliases))
+                .collect(),
+        });
+        return Ok(());
+    }
+    println!(
+        "VID:PID:Serial (name) [alias] listing of {} available debug probe{}:",
+        probes.len(),
+        if probes.len().eq(&1) { "" } else { "s" }
+    );
+    for probe in probes {
+        println!("  - {}", describe_probe(&probe, &aliases));
+    }
+    Ok(())
+}
+
+fn list(args: &Args, list_args: &ListArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let storage = connect_storage(args, base_dirs)?;
+    let list_query = ListQuery {
+        sort: list_args.sort,
+        reverse: list_args.reverse,
+        limit: list_args.limit,
+        filter: list_args.filter.clone().or(list_args.filter_regex.clone()),
+        since: list_args.since.map(local_timestamp).transpose()?,
+    };
 
     /* firmware names list command */
-    if args.list && args.firmware_name.is_none() {
+    let Some(ref firmware_name) = list_args.firmware_name else {
+        let firmwares = storage
+            .list_firmwares()
+            .context("Failed to fetch firmware names from the Bucket")?;
+        let firmwares = list_query.apply(firmwares, ListField::Name);
+        if args.output == OutputFormat::Json {
+            print_json(&FirmwareListOutput {
+                firmwares: &firmwares,
+            });
+            return Ok(());
+        }
         println!(
             "Listing {} available firmware name{}:",
             firmwares.len(),
             if firmwares.len().eq(&1) { "" } else { "s" }
         );
-        for (name, timestamp) in firmwares.iter().zip(firmwares_modified.iter()) {
-            if args.dates {
-                println!(
-                    "  - {} ({})",
-                    name,
-                    DateTime::from_timestamp(*timestamp, 0)
-                        .ok_or(anyhow::anyhow!("not a timestamp"))?
-                );
-            } else {
-                println!("  - {}", name);
-            }
+        for f in firmwares.iter() {
+            println!("{}", list_entry(&f.name, f, list_args.dates)?);
         }
-        exit(0);
+        return Ok(());
+    };
+
+    /* firmware version list command */
+    /* tags come from the manifests, so they are only read when needed and
+     * without a channel only for the versions that are listed */
+    let read_tags = |versions: &mut [FirmwareMetadata]| {
+        storage
+            .read_tags(firmware_name, versions, &base_dirs.firmware_cache_dir)
+            .context("Failed to read firmware tags from the Bucket")
+    };
+    let mut versions = list_versions(&storage, firmware_name)?;
+    if let Some(ref channel) = list_args.channel {
+        read_tags(&mut versions)?;
+        versions.retain(|f| f.has_tag(channel));
+    }
+    let mut versions = list_query.apply(versions, ListField::Version);
+    if list_args.tags && list_args.channel.is_none() {
+        read_tags(&mut versions)?;
+    }
+    if args.output == OutputFormat::Json {
+        print_json(&VersionListOutput {
+            name: firmware_name,
+            channel: list_args.channel.as_deref(),
+            versions: &versions,
+        });
+        return Ok(());
+    }
+    println!(
+        "Listing {} version{} of firmware \"{}\"{}",
+        versions.len(),
+        if versions.len().eq(&1) { "" } else { "s" },
+        firmware_name,
+        list_args
+            .channel
+            .as_ref()
+            .map_or(String::new(), |c| format!(" on channel \"{}\"", c))
+    );
+    for f in versions.iter() {
+        println!("{}", list_entry(&f.version, f, list_args.dates)?);
+    }
+    Ok(())
+}
+
+fn list_versions(storage: &Storage, firmware_name: &str) -> anyhow::Result<Vec<FirmwareMetadata>> {
+    let versions = storage
+        .list_firmware_versions(firmware_name)
+        .context("Failed to fetch firmware versions from the Bucket")?;
+    if versions.is_empty() {
+        anyhow::bail!(
+            "Firmware name \"{}\" not found in the Bucket (you can use the list command to list all names)",
+            firmware_name
+        );
     }
+    Ok(versions)
+}
 
-    /* firmware name sanity checks */
-    let firmware_name = match args.firmware_name {
-        Some(n) => {
-            if !firmwares.contains(&n) {
-                anyhow::bail!("Firmware name \"{}\" not found in the Bucket", n)
+/// Resolves the version given on the command line, optionally limited to
+/// the versions on `channel`, to a concrete version of the firmware.
+fn select_firmware_version(
+    storage: &Storage,
+    firmware_name: &str,
+    firmware_version: Option<&str>,
+    channel: Option<&str>,
+    base_dirs: &BaseDirs,
+) -> anyhow::Result<FirmwareMetadata> {
+    let mut versions = list_versions(storage, firmware_name)?;
+    let on_channel = match channel {
+        Some(channel) => {
+            storage
+                .read_tags(firmware_name, &mut versions, &base_dirs.firmware_cache_dir)
+                .context("Failed to read firmware tags from the Bucket")?;
+            versions.retain(|f| f.has_tag(channel));
+            if versions.is_empty() {
+                anyhow::bail!(
+                    "No version of firmware \"{}\" is on channel \"{}\"",
+                    firmware_name,
+                    channel
+                )
             }
-            n
+            format!(" on channel \"{}\"", channel)
         }
-        None => {
+        None => String::new(),
+    };
+
+    let version = match (firmware_version, channel) {
+        (Some(v), _) => {
+            let resolved = resolve_version(v, &versions).with_context(|| {
+                format!(
+                    "Firmware version \"{}\" not found{} in the Bucket",
+                    v, on_channel
+                )
+            })?;
+            print_resolved_version(v, &resolved);
+            resolved
+        }
+        (None, Some(channel)) => {
+            let resolved = resolve_version(LATEST, &versions)?;
+            eprintln!("Resolved channel \"{}\" to {}", channel, resolved);
+            resolved
+        }
+        (None, None) => {
             anyhow::bail!(
-                "Please specify firmware name to continue (you can use the --list option to list all names)"
+                "Please specify firmware version to continue (you can use the list command to list all versions)"
             )
         }
     };
+    Ok(versions.into_iter().find(|f| f.version == version).unwrap())
+}
 
-    let (versions, versions_modified) = storage
-        .list_firmware_versions(&firmware_name)
-        .context("Failed to fetch firmware versions from the Bucket")?
-        .iter()
-        .map(|f| (f.version.clone(), f.last_modified))
-        .collect::<(Vec<String>, Vec<i64>)>();
+fn info(args: &Args, info_args: &InfoArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let storage = connect_storage(args, base_dirs)?;
+    let metadata = select_firmware_version(
+        &storage,
+        &info_args.firmware_name,
+        info_args.firmware_version.as_deref(),
+        info_args.channel.as_deref(),
+        base_dirs,
+    )?;
+    let firmware = storage
+        .download_firmware(
+            &metadata.name,
+            &metadata.version,
+            &base_dirs.firmware_cache_dir,
+        )
+        .context("Failed to download firmware")?;
 
-    /* firmware version list command */
-    if args.list && args.firmware_version.is_none() {
+    if args.output == OutputFormat::Json {
+        print_json(&InfoOutput {
+            metadata: &metadata,
+            firmware: &firmware,
+        });
+        return Ok(());
+    }
+
+    println!(
+        "Firmware \"{}\" version {}",
+        firmware.name, firmware.version
+    );
+    println!(
+        "  - uploaded: {}",
+        DateTime::from_timestamp(metadata.last_modified, 0)
+            .ok_or(anyhow::anyhow!("not a timestamp"))?
+    );
+    if !metadata.tags.is_empty() {
+        println!("  - tags: {}", metadata.tags.join(", "));
+    }
+    println!("  - chip: {}", firmware.chip);
+    if !firmware.compatible_chips.is_empty() {
         println!(
-            "Listing {} version{} of firmware \"{}\"",
-            versions.len(),
-            if versions.len().eq(&1) { "" } else { "s" },
-            firmware_name
+            "  - compatible chips: {}",
+            firmware.compatible_chips.join(", ")
+        );
+    }
+    if let Some(unique_id) = firmware.unique_id {
+        println!(
+            "  - unique ID: {} bytes at {:#010x}",
+            unique_id.length, unique_id.address
+        );
+    }
+    if !firmware.provisioning.is_empty() {
+        println!(
+            "  - provisioning: {}",
+            firmware
+                .provisioning
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
         );
-        for (version, timestamp) in versions.iter().zip(versions_modified.iter()) {
-            if args.dates {
+    }
+    if !firmware.option_bytes.is_empty() {
+        println!("  - option byte writes: {}", firmware.option_bytes.len());
+    }
+    if let Some(ref path) = firmware.target_description {
+        println!("  - target description: {}", path.display());
+    }
+    println!("  - cached at: {}", firmware.path.display());
+    Ok(())
+}
+
+fn storage(args: &Args, command: &StorageCommand, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let creds_manager = CredentialsManager::new(base_dirs.creds_dir.clone());
+    match command {
+        StorageCommand::Add(add_args) => {
+            let creds_manager = creds_manager.with_secret_store(add_args.secret_store);
+            let known = PartialCredentials {
+                user_storage_name: add_args.name.clone(),
+                storage_name: add_args.bucket.clone(),
+                storage_account_id: add_args.account_id.clone(),
+                storage_access_key: add_args.access_key.clone(),
+                storage_secret_key: add_args.secret_key.clone(),
+            };
+            let creds = enter_credentials(known, !add_args.skip_check)?;
+            creds_manager
+                .add(creds)
+                .context("Failed to save new credentials")?;
+            eprintln!("Credentials saved successfully");
+        }
+        StorageCommand::Remove { name } => {
+            creds_manager.remove(name)?;
+            eprintln!("Removed storage \"{}\"", name);
+        }
+        StorageCommand::List => {
+            let all_creds = creds_manager
+                .get_all_stored()
+                .context("Failed to load saved credentials")?;
+            if args.output == OutputFormat::Json {
+                print_json(&StorageListOutput {
+                    storages: all_creds.iter().map(StorageSummary::new).collect(),
+                });
+                return Ok(());
+            }
+            println!(
+                "Listing {} saved storage{}:",
+                all_creds.len(),
+                if all_creds.len().eq(&1) { "" } else { "s" }
+            );
+            for summary in all_creds.iter().map(StorageSummary::new) {
                 println!(
-                    "  - {} ({})",
-                    version,
-                    DateTime::from_timestamp(*timestamp, 0)
-                        .ok_or(anyhow::anyhow!("not a timestamp"))?
+                    "  - {} ({:?} bucket \"{}\", {}, access key {}, secret key {})",
+                    summary.name,
+                    summary.storage_type,
+                    summary.bucket,
+                    match summary.endpoint {
+                        Some(endpoint) => format!("endpoint {}", endpoint),
+                        None => format!("account ID {}", summary.account_id),
+                    },
+                    summary.access_key,
+                    match summary.secret_key {
+                        Some(masked) => masked,
+                        None => format!("in the {} store", summary.secret_store),
+                    }
                 );
-            } else {
-                println!("  - {}", version);
             }
         }
-        exit(0);
-    } else if args.list && args.firmware_version.is_some() {
-        anyhow::bail!("Invalid use of the --list command")
+        StorageCommand::Rename { name, new_name } => {
+            creds_manager.rename(name, new_name)?;
+            eprintln!("Renamed storage \"{}\" to \"{}\"", name, new_name);
+        }
+        StorageCommand::Test { name } => {
+            /* secrets are only read for the storages that are tested */
+            let names = creds_manager
+                .get_all_stored()
+                .context("Failed to load saved credentials")?
+                .into_iter()
+                .map(|c| c.user_storage_name)
+                .filter(|n| name.as_ref().is_none_or(|name| n == name))
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                anyhow::bail!("No matching storage found");
+            }
+            let mut failed = 0;
+            for name in names.iter() {
+                eprintln!("Testing \"{}\" storage...", name);
+                let result = creds_manager
+                    .get(name)
+                    .context("Failed to load saved credentials")
+                    .and_then(|creds| Storage::new(&creds))
+                    .and_then(|s| {
+                        s.is_available()?;
+                        s.list_firmwares()
+                            .context("Failed to list firmware in the Bucket")
+                    });
+                match result {
+                    Ok(firmwares) => eprintln!(
+                        "Storage \"{}\" is available, it contains {} firmware name{}",
+                        name,
+                        firmwares.len(),
+                        if firmwares.len().eq(&1) { "" } else { "s" }
+                    ),
+                    Err(ref e) => {
+                        failed += 1;
+                        eprintln!("Storage \"{}\" is not available: {:#}", name, e);
+                    }
+                }
+            }
+            if failed > 0 {
+                anyhow::bail!("{} of {} storages are not available", failed, names.len());
+            }
+        }
+        StorageCommand::Export {
+            name,
+            file,
+            protect,
+        } => {
+            let creds = creds_manager.get(name)?;
+            let passphrase = match protect {
+                true => Some(read_passphrase("Passphrase to protect the token: ", true)?),
+                false => None,
+            };
+            let token = creds.to_token(passphrase.as_deref())?;
+            if passphrase.is_none() {
+                eprintln!("Warning: the token contains the secret key, share it only with people who should have access to the bucket");
+            }
+            match file {
+                Some(path) => {
+                    fs::write(path, format!("{}\n", token))
+                        .context("Failed to write token file")?;
+                    eprintln!("Saved token to {}", path.display());
+                }
+                None if args.output == OutputFormat::Json => {
+                    print_json(&TokenOutput { name, token })
+                }
+                None => println!("{}", token),
+            }
+        }
+        StorageCommand::Import(import_args) => {
+            let token = match Path::new(&import_args.token).is_file() {
+                true => {
+                    fs::read_to_string(&import_args.token).context("Failed to read token file")?
+                }
+                false => import_args.token.clone(),
+            };
+            let passphrase = match Credentials::is_protected_token(&token) {
+                true => Some(read_passphrase("Passphrase of the token: ", false)?),
+                false => None,
+            };
+            let mut creds = Credentials::from_token(&token, passphrase.as_deref())?;
+            if let Some(name) = &import_args.name {
+                creds.user_storage_name = name.clone();
+            }
+            if !import_args.skip_check {
+                check_credentials(&creds)
+                    .context("Credentials check failed (use --skip-check to import anyway)")?;
+            }
+            let name = creds.user_storage_name.clone();
+            creds_manager
+                .with_secret_store(import_args.secret_store)
+                .add(creds)
+                .context(
+                    "Failed to save imported credentials (use --name to import under another name)",
+                )?;
+            eprintln!("Imported storage \"{}\"", name);
+        }
+        StorageCommand::Migrate { store, name } => {
+            let names = match name {
+                Some(name) => vec![name.clone()],
+                None => creds_manager
+                    .get_all_stored()
+                    .context("Failed to load saved credentials")?
+                    .into_iter()
+                    .map(|c| c.user_storage_name)
+                    .collect(),
+            };
+            for name in names.iter() {
+                match creds_manager
+                    .migrate(name, *store)
+                    .context(format!("Failed to migrate storage \"{}\"", name))?
+                {
+                    true => eprintln!(
+                        "Moved the secret key of \"{}\" to the {} store",
+                        name, store
+                    ),
+                    false => eprintln!(
+                        "Secret key of \"{}\" is already in the {} store",
+                        name, store
+                    ),
+                }
+            }
+        }
     }
+    Ok(())
+}
 
-    /* firmware version sanity checks */
-    let firmware_version = match args.firmware_version {
-        Some(v) => {
-            if !versions.contains(&v) {
-                anyhow::bail!("Firmware version \"{}\" not found in the Bucket", v)
+fn clear_cache(base_dirs: &BaseDirs, firmware_name: Option<&str>) -> anyhow::Result<()> {
+    match firmware_name {
+        Some(name) => {
+            if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+                anyhow::bail!("Invalid firmware name \"{}\"", name);
             }
-            v
+            let path = base_dirs.firmware_cache_dir.join(name);
+            if !path.exists() {
+                anyhow::bail!("Firmware \"{}\" is not cached", name);
+            }
+            eprintln!("Clearing cache of firmware \"{}\"...", name);
+            fs::remove_dir_all(path).context("Failed to clear firmware cache directory")
         }
         None => {
-            anyhow::bail!(
-                "Please specify firmware version to continue (you can use the --list option to list all versions)"
-            )
+            eprintln!("Clearing cache directory...");
+            base_dirs
+                .clear_firmware_cache()
+                .context("Failed to clear firmware cache directory")
         }
+    }
+}
+
+fn cache(args: &Args, command: &CacheCommand, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    match command {
+        CacheCommand::Clear { firmware_name } => clear_cache(base_dirs, firmware_name.as_deref()),
+        CacheCommand::List => {
+            let mut cached = Vec::new();
+            if base_dirs.firmware_cache_dir.exists() {
+                for name in fs::read_dir(&base_dirs.firmware_cache_dir)? {
+                    let name = name?;
+                    if !name.file_type()?.is_dir() {
+                        continue;
+                    }
+                    for version in fs::read_dir(name.path())? {
+                        let path = version?.path();
+                        if path.join("firmware.elf").exists() {
+                            cached.push(CachedFirmware {
+                                name: name.file_name().to_string_lossy().into_owned(),
+                                version: path
+                                    .file_name()
+                                    .unwrap_or_default()
+                                    .to_string_lossy()
+                                    .into_owned(),
+                                path,
+                            });
+                        }
+                    }
+                }
+            }
+            cached.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+            if args.output == OutputFormat::Json {
+                print_json(&CacheListOutput { cached: &cached });
+                return Ok(());
+            }
+            println!(
+                "Listing {} cached firmware version{} in {}:",
+                cached.len(),
+                if cached.len().eq(&1) { "" } else { "s" },
+                base_dirs.firmware_cache_dir.display()
+            );
+            for c in cached {
+                println!("  - {}/{}", c.name, c.version);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn flash(args: &Args, flash_args: &FlashArgs, base_dirs: &BaseDirs) -> anyhow::Result<()> {
+    let Some(ref firmware_name) = flash_args.firmware_name else {
+        anyhow::bail!(
+            "Please specify firmware name to continue (you can use the list command to list all names)"
+        )
     };
+    let storage = connect_storage(args, base_dirs)?;
+    let firmware_version = select_firmware_version(
+        &storage,
+        firmware_name,
+        flash_args.firmware_version.as_deref(),
+        flash_args.channel.as_deref(),
+        base_dirs,
+    )?
+    .version;
 
     /* Finally onto the firmware flashing itself */
     let probes = get_probes()?;
-
-    // Use the first probe found.
-    let probe = match args.probe {
-        //TODO add VID:PID:Serial parsing
-        Some(ref p) => probes
-            .iter()
-            .find(|probe| probe.serial_number.as_ref().expect("Probe without serial") == p)
-            .context("Probe not found")?,
-        None => &probes[0],
-    };
-    let probe = probe.open().context("Failed to open probe")?;
+    let aliases = ProbeAliases::read_from_path(&base_dirs.probe_aliases_path)?;
+    let selected = select_probes(&probes, &flash_args.connect.probe, &aliases)?;
 
     let firmware = storage
         .download_firmware(
-            &firmware_name,
+            firmware_name,
             &firmware_version,
             &base_dirs.firmware_cache_dir,
         )
         .context("Failed to download firmware")?;
 
-    flash_firmware(probe, firmware, args.connect_under_reset, &|s| {
-        eprintln!("{}", s);
-    })?;
+    if flash_args.option_bytes_dry_run {
+        let [probe] = selected[..] else {
+            anyhow::bail!("The --option-bytes-dry-run option can only be used with a single probe");
+        };
+        let probe = probe.open().context("Failed to open probe")?;
+        return preview_option_bytes(
+            probe,
+            &firmware,
+            flash_args.connect.connect_under_reset,
+            &|s| {
+                eprintln!("{}", s);
+            },
+        );
+    }
+
+    let flash_options = flash_options(
+        &flash_args.connect,
+        &flash_args.program,
+        &firmware,
+        base_dirs,
+    )?;
+    let flash_log = FlashLog {
+        history: History::new(base_dirs.history_path.clone()),
+        storage: storage.name(),
+        aliases: &aliases,
+    };
+
+    if flash_args.loop_mode {
+        let [probe] = selected[..] else {
+            anyhow::bail!("The --loop option can only be used with a single probe");
+        };
+        return flash_loop(probe, &firmware, &flash_options, &flash_log, args.output);
+    }
+
+    if let [probe] = selected[..] {
+        let result = probe.open().context("Failed to open probe").and_then(|p| {
+            flash_firmware(p, firmware.clone(), &flash_options, &|s| {
+                eprintln!("{}", s);
+            })
+        });
+        let record = flash_log.record(&firmware, probe, &result);
+        if args.output == OutputFormat::Json {
+            print_json(&FlashOutput {
+                results: vec![record],
+            });
+            /* the error is part of the record, it gets no document of its own */
+            if result.is_err() {
+                exit(1);
+            }
+        }
+        result?;
+        if flash_args.monitor {
+            return monitor(
+                probe,
+                &firmware,
+                flash_args.log_file.as_deref(),
+                args.output,
+            );
+        }
+        return Ok(());
+    }
+
+    if flash_args.monitor {
+        anyhow::bail!("The --monitor option can only be used with a single probe");
+    }
+
+    /* gang programming of multiple probes */
+    let labels = selected
+        .iter()
+        .map(|p| probe_label(p, &aliases))
+        .collect::<Vec<String>>();
+    eprintln!("Flashing {} probes in parallel...", selected.len());
+    let results = flash_firmware_on_probes(&selected, &firmware, &flash_options, &|i, s| {
+        eprintln!("[{}] {}", labels[i], s);
+    });
+
+    let records = selected
+        .iter()
+        .zip(&results)
+        .map(|(probe, result)| flash_log.record(&firmware, probe, result))
+        .collect::<Vec<FlashRecord>>();
+
+    if args.output == OutputFormat::Json {
+        print_json(&FlashOutput { results: records });
+    } else {
+        let width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+        println!("{:<width$}  Result", "Probe");
+        for (label, result) in labels.iter().zip(&results) {
+            match result {
+                Ok(report) => println!(
+                    "{:<width$}  OK{}{}",
+                    label,
+                    match report.outcome {
+                        FlashOutcome::Programmed => "",
+                        FlashOutcome::Unchanged => " (unchanged)",
+                    },
+                    report
+                        .device_id
+                        .as_ref()
+                        .map_or(String::new(), |id| format!(", device ID {}", id))
+                ),
+                Err(e) => println!("{:<width$}  FAILED: {:#}", label, e),
+            }
+        }
+    }
+
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    if failed > 0 && args.output == OutputFormat::Json {
+        exit(1);
+    }
+    if failed > 0 {
+        anyhow::bail!("Flashing failed on {} of {} probes", failed, results.len());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_args() {
+        Args::command().debug_assert();
+
+        let args = Args::try_parse_from([
+            "quick-flash",
+            "dump",
+            "board.hex",
+            "--chip",
+            "nRF52840_xxAA",
+            "--output",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(args.output, OutputFormat::Json);
+        assert!(
+            matches!(args.command, Some(Command::Dump(ref dump)) if dump.file == Path::new("board.hex"))
+        );
+
+        let args = Args::try_parse_from(["quick-flash", "blinky", "v1.2.0"]).unwrap();
+        assert_eq!(args.flash.firmware_name.as_deref(), Some("blinky"));
+        assert_eq!(args.flash.firmware_version.as_deref(), Some("v1.2.0"));
+
+        /* replaced options still parse so that they can point to their subcommands */
+        for argv in [
+            &["quick-flash", "--list"][..],
+            &["quick-flash", "-l"],
+            &["quick-flash", "blinky", "--list"],
+            &["quick-flash", "--list-probes"],
+        ] {
+            let args = Args::try_parse_from(argv).unwrap();
+            assert!(args.list || args.list_probes, "{:?}", argv);
+        }
+        let args = Args::try_parse_from(["quick-flash", "--clear-cache"]).unwrap();
+        assert!(args.clear_cache && !args.list && !args.list_probes);
+
+        let argv = ["quick-flash", "list", "--output", "json"].map(String::from);
+        assert_eq!(requested_output(&argv), OutputFormat::Json);
+        assert_eq!(requested_output(&argv[..3]), OutputFormat::Text);
+        assert!(Args::try_parse_from(["quick-flash", "--if-changed"]).is_err());
+
+        /* flashing options are only accepted where they apply */
+        let args =
+            Args::try_parse_from(["quick-flash", "blinky", "--probe", "a,b", "--force"]).unwrap();
+        assert_eq!(args.flash.connect.probe.len(), 2);
+        assert!(args.flash.program.force);
+        assert!(
+            Args::try_parse_from(["quick-flash", "run", "tests", "v1", "-r", "--if-changed"])
+                .is_ok()
+        );
+        assert!(Args::try_parse_from(["quick-flash", "history", "--probe", "rig-3"]).is_ok());
+        assert!(Args::try_parse_from(["quick-flash", "list", "--if-changed"]).is_err());
+        assert!(
+            Args::try_parse_from(["quick-flash", "storage", "list", "--probe", "rig-3"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(0), 0);
+        assert_eq!(exit_code(3), 3);
+        assert_eq!(exit_code(255), 255);
+        assert_eq!(exit_code(256), 1);
+        assert_eq!(exit_code(512), 1);
+        assert_eq!(exit_code(-1), 1);
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{self, Context};
+use defmt_decoder::{DecodeError, StreamDecoder, Table};
+use object::{Object, ObjectSymbol};
+use probe_rs::rtt::{Rtt, ScanRegion};
+use probe_rs::Core;
+use std::time::{Duration, Instant};
+
+/// Name of the RTT control block symbol placed by the RTT target libraries.
+const RTT_SYMBOL: &str = "_SEGGER_RTT";
+
+/// Address of the RTT control block in the firmware image, if it can be found.
+pub fn rtt_address(elf: &[u8]) -> Option<u64> {
+    let file = object::File::parse(elf).ok()?;
+    file.symbols()
+        .find(|s| s.name() == Ok(RTT_SYMBOL))
+        .map(|s| s.address())
+}
+
+/// Turns the raw bytes of an RTT up channel into log lines, either by
+/// splitting text at line breaks or by decoding defmt frames.
+pub enum ChannelDecoder<'t> {
+    Text(Vec<u8>),
+    Defmt(Box<dyn StreamDecoder + Send + Sync + 't>),
+}
+
+impl<'t> ChannelDecoder<'t> {
+    /// Picks the decoder for a channel, defmt is used for the channel named
+    /// "defmt" (or the unnamed channel 0) when the firmware contains defmt data.
+    pub fn new(table: Option<&'t Table>, number: usize, name: Option<&str>) -> Self {
+        match table {
+            Some(table) if name == Some("defmt") || (name.is_none() && number == 0) => {
+                ChannelDecoder::Defmt(table.new_stream_decoder())
+            }
+            _ => ChannelDecoder::Text(Vec::new()),
+        }
+    }
+
+    /// Feeds received bytes to the decoder and returns all completed lines.
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        match self {
+            ChannelDecoder::Text(buffer) => {
+                buffer.extend_from_slice(data);
+                while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                    let line = buffer.drain(..=end).collect::<Vec<u8>>();
+                    lines.push(
+                        String::from_utf8_lossy(&line)
+                            .trim_end_matches(['\r', '\n'])
+                            .to_string(),
+                    );
+                }
+            }
+            ChannelDecoder::Defmt(decoder) => {
+                decoder.received(data);
+                loop {
+                    match decoder.decode() {
+                        Ok(frame) => lines.push(frame.display(false).to_string()),
+                        Err(DecodeError::UnexpectedEof) => break,
+                        Err(DecodeError::Malformed) => {
+                            lines.push("(malformed defmt frame)".to_string());
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Reads the RTT up channels of a running target and decodes them into lines.
+pub struct RttReader<'t> {
+    rtt: Rtt,
+    channels: Vec<(String, ChannelDecoder<'t>)>,
+    buffer: Vec<u8>,
+}
+
+impl<'t> RttReader<'t> {
+    /// Looks for the RTT control block of the firmware in `elf`, waiting up
+    /// to `timeout` for the firmware to set it up after reset.
+    pub fn attach(
+        core: &mut Core,
+        elf: &[u8],
+        table: Option<&'t Table>,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let region = match rtt_address(elf) {
+            Some(address) => ScanRegion::Exact(address),
+            None => ScanRegion::Ram,
+        };
+
+        let start = Instant::now();
+        let mut rtt = loop {
+            match Rtt::attach_region(core, &region) {
+                Ok(rtt) => break rtt,
+                Err(e) if start.elapsed() > timeout => {
+                    return Err(e).context("Failed to find the RTT control block");
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        };
+
+        let channels = rtt
+            .up_channels()
+            .iter()
+            .map(|c| {
+                let name = c
+                    .name()
+                    .map_or_else(|| c.number().to_string(), str::to_owned);
+                (name, ChannelDecoder::new(table, c.number(), c.name()))
+            })
+            .collect();
+        Ok(RttReader {
+            rtt,
+            channels,
+            buffer: vec![0u8; 1024],
+        })
+    }
+
+    pub fn channel_names(&self) -> Vec<&str> {
+        self.channels
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Reads all channels once and passes the completed lines to
+    /// `line_callback`, returns whether any data was received.
+    pub fn poll(
+        &mut self,
+        core: &mut Core,
+        line_callback: &mut dyn FnMut(&str, String),
+    ) -> anyhow::Result<bool> {
+        let mut received = false;
+        for (channel, (name, decoder)) in self.rtt.up_channels().iter_mut().zip(&mut self.channels)
+        {
+            let count = channel
+                .read(core, &mut self.buffer)
+                .context("Failed to read RTT channel")?;
+            received |= count > 0;
+            for line in decoder.push(&self.buffer[..count]) {
+                line_callback(name, line);
+            }
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_decoder() {
+        let mut decoder = ChannelDecoder::new(None, 0, Some("Terminal"));
+        assert_eq!(decoder.push(b"Hello"), Vec::<String>::new());
+        assert_eq!(decoder.push(b", world\r\nbo"), vec!["Hello, world"]);
+        assert_eq!(decoder.push(b"ot\n\n"), vec!["boot", ""]);
+        assert_eq!(rtt_address(b"not an elf"), None);
+    }
+}
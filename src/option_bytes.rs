@@ -0,0 +1,377 @@
+use crate::utils;
+use anyhow::{self, Context};
+use probe_rs::{MemoryInterface, Session};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// A write of a configuration register declared in the firmware manifest,
+/// applied after the firmware is programmed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ConfigWrite {
+    /// Field of the FLASH_OPTR option bytes register of the STM32L4, STM32G0
+    /// and STM32G4 families, given either by name or by a raw bit mask
+    Stm32Option {
+        #[serde(default)]
+        field: Option<String>,
+        #[serde(default, deserialize_with = "utils::deserialize_optional_number")]
+        mask: Option<u64>,
+        #[serde(deserialize_with = "utils::deserialize_number")]
+        value: u64,
+    },
+    /// Register of the nRF52 UICR, given by name or by its offset, written through the NVMC
+    NrfUicr {
+        register: String,
+        #[serde(deserialize_with = "utils::deserialize_number")]
+        value: u64,
+    },
+    /// Plain 32-bit register write, only the masked bits are modified if a mask is given
+    Register {
+        #[serde(deserialize_with = "utils::deserialize_number")]
+        address: u64,
+        #[serde(default, deserialize_with = "utils::deserialize_optional_number")]
+        mask: Option<u64>,
+        #[serde(deserialize_with = "utils::deserialize_number")]
+        value: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteMethod {
+    Stm32Optr,
+    NrfUicr,
+    Register,
+}
+
+/// A [`ConfigWrite`] resolved for a particular chip, see [`plan_writes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedWrite {
+    pub description: String,
+    pub address: u64,
+    pub mask: u32,
+    pub value: u32,
+    method: WriteMethod,
+}
+
+const STM32_FLASH: u64 = 0x4002_2000;
+const STM32_FLASH_KEYR: u64 = STM32_FLASH + 0x08;
+const STM32_FLASH_OPTKEYR: u64 = STM32_FLASH + 0x0C;
+const STM32_FLASH_SR: u64 = STM32_FLASH + 0x10;
+const STM32_FLASH_CR: u64 = STM32_FLASH + 0x14;
+const STM32_FLASH_OPTR: u64 = STM32_FLASH + 0x20;
+/// FLASH_SR error flags, from OPERR to FASTERR plus RDERR and OPTVERR
+const STM32_FLASH_SR_ERRORS: u32 = 0b1100_0011_1111_1010;
+const STM32_FLASH_SR_BSY: u32 = 1 << 16;
+
+const NRF_NVMC_READY: u64 = 0x4001_E400;
+const NRF_NVMC_CONFIG: u64 = 0x4001_E504;
+const NRF_UICR: u64 = 0x1000_1000;
+
+/// Named FLASH_OPTR fields as (name, mask), the watchdog bits are shared by all
+/// supported families while the brown-out reset fields differ.
+fn stm32_optr_fields(chip: &str) -> Option<Vec<(&'static str, u32)>> {
+    let mut fields = vec![
+        ("rdp", 0xFF),
+        ("iwdg_sw", 1 << 16),
+        ("iwdg_stop", 1 << 17),
+        ("iwdg_stdby", 1 << 18),
+        ("wwdg_sw", 1 << 19),
+    ];
+    let chip = chip.to_uppercase();
+    if chip.starts_with("STM32G0") {
+        fields.extend([
+            ("boren", 1 << 8),
+            ("borf_lev", 0b11 << 9),
+            ("borr_lev", 0b11 << 11),
+        ]);
+    } else if chip.starts_with("STM32L4") || chip.starts_with("STM32G4") {
+        fields.push(("bor_lev", 0b111 << 8));
+    } else {
+        return None;
+    }
+    Some(fields)
+}
+
+fn nrf_uicr_offset(register: &str) -> Option<u64> {
+    let register = register.to_lowercase();
+    if let Some(n) = register.strip_prefix("customer") {
+        return n
+            .parse::<u64>()
+            .ok()
+            .filter(|n| *n < 32)
+            .map(|n| 0x80 + 4 * n);
+    }
+    match register.as_str() {
+        "pselreset0" => Some(0x200),
+        "pselreset1" => Some(0x204),
+        "approtect" => Some(0x208),
+        "nfcpins" => Some(0x20C),
+        "debugctrl" => Some(0x210),
+        "regout0" => Some(0x304),
+        _ => utils::parse_number(&register).ok(),
+    }
+}
+
+fn to_u32(value: u64, what: &str) -> anyhow::Result<u32> {
+    u32::try_from(value).context(format!(
+        "Value {:#x} of {} does not fit into 32 bits",
+        value, what
+    ))
+}
+
+/// Resolves the configuration writes for `chip`, all STM32 option byte
+/// fields are merged into a single FLASH_OPTR write.
+pub fn plan_writes(chip: &str, writes: &[ConfigWrite]) -> anyhow::Result<Vec<PlannedWrite>> {
+    let mut planned = Vec::<PlannedWrite>::new();
+    let mut optr: Option<PlannedWrite> = None;
+
+    for write in writes {
+        match write {
+            ConfigWrite::Stm32Option { field, mask, value } => {
+                let fields = stm32_optr_fields(chip).context(format!(
+                    "STM32 option bytes are not supported for chip {}, use a \"register\" write instead",
+                    chip
+                ))?;
+                let (name, mask) = match (field, mask) {
+                    (Some(field), None) => fields
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(field))
+                        .map(|(name, mask)| (name.to_string(), *mask))
+                        .context(format!(
+                            "Unknown option byte field \"{}\" of chip {}, expected one of {}",
+                            field,
+                            chip,
+                            fields
+                                .iter()
+                                .map(|(name, _)| *name)
+                                .collect::<Vec<&str>>()
+                                .join(", ")
+                        ))?,
+                    (None, Some(mask)) => {
+                        let mask = to_u32(*mask, "an option byte mask")?;
+                        (format!("{:#010x}", mask), mask)
+                    }
+                    _ => anyhow::bail!("STM32 option byte write needs either a field or a mask"),
+                };
+                let shifted = to_u32(*value, &name)?
+                    .checked_shl(mask.trailing_zeros())
+                    .filter(|v| v & !mask == 0)
+                    .context(format!("Value {:#x} does not fit into {}", value, name))?;
+                if mask & 0xFF == 0xFF && shifted & 0xFF == 0xCC {
+                    anyhow::bail!("Refusing to set RDP level 2, it permanently locks the chip");
+                }
+
+                let optr = optr.get_or_insert(PlannedWrite {
+                    description: "FLASH_OPTR".to_string(),
+                    address: STM32_FLASH_OPTR,
+                    mask: 0,
+                    value: 0,
+                    method: WriteMethod::Stm32Optr,
+                });
+                if optr.mask & mask != 0 {
+                    anyhow::bail!("Option byte field {} overlaps another field", name);
+                }
+                optr.description
+                    .push_str(&format!(" {}={:#x}", name, value));
+                optr.mask |= mask;
+                optr.value |= shifted;
+            }
+            ConfigWrite::NrfUicr { register, value } => {
+                if !chip.to_lowercase().starts_with("nrf52") {
+                    anyhow::bail!(
+                        "nRF UICR writes are only supported for nRF52 chips, not {}",
+                        chip
+                    );
+                }
+                let offset = nrf_uicr_offset(register)
+                    .filter(|o| *o < 0x400 && o % 4 == 0)
+                    .context(format!("Unknown UICR register \"{}\"", register))?;
+                planned.push(PlannedWrite {
+                    description: format!("UICR {}", register.to_uppercase()),
+                    address: NRF_UICR + offset,
+                    mask: u32::MAX,
+                    value: to_u32(*value, register)?,
+                    method: WriteMethod::NrfUicr,
+                });
+            }
+            ConfigWrite::Register {
+                address,
+                mask,
+                value,
+            } => {
+                let mask = to_u32(mask.unwrap_or(u32::MAX as u64), "a register mask")?;
+                let value = to_u32(*value, "a register write")?;
+                if value & !mask != 0 {
+                    anyhow::bail!("Value {:#x} has bits outside of mask {:#x}", value, mask);
+                }
+                planned.push(PlannedWrite {
+                    description: format!("register {:#010x}", address),
+                    address: *address,
+                    mask,
+                    value,
+                    method: WriteMethod::Register,
+                });
+            }
+        }
+    }
+
+    /* reloading the option bytes resets the target, so they go last */
+    planned.extend(optr);
+    Ok(planned)
+}
+
+fn wait_until(
+    session: &mut Session,
+    address: u64,
+    done: impl Fn(u32) -> bool,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    while !done(session.core(0)?.read_word_32(address)?) {
+        if start.elapsed() > Duration::from_secs(1) {
+            anyhow::bail!("Timed out waiting for the flash controller");
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+/// Applies the planned writes whose bits differ from the target, or only
+/// reports them if `dry_run` is set.
+///
+/// Returns true when the target has been reset by reloading the STM32
+/// option bytes.
+pub fn apply_writes(
+    session: &mut Session,
+    writes: &[PlannedWrite],
+    dry_run: bool,
+    progress_callback: &dyn Fn(String),
+) -> anyhow::Result<bool> {
+    let mut reset = false;
+    for write in writes {
+        let current = session
+            .core(0)?
+            .read_word_32(write.address)
+            .context(format!("Failed to read {}", write.description))?;
+        let new = (current & !write.mask) | write.value;
+        if new == current {
+            progress_callback(format!("{} is already set", write.description));
+            continue;
+        }
+        progress_callback(format!(
+            "{} {} ({:#010x}): {:#010x} -> {:#010x}",
+            if dry_run { "Would write" } else { "Writing" },
+            write.description,
+            write.address,
+            current,
+            new
+        ));
+        if dry_run {
+            continue;
+        }
+
+        match write.method {
+            WriteMethod::Register => {
+                session.core(0)?.write_word_32(write.address, new)?;
+            }
+            WriteMethod::NrfUicr => {
+                /* flash bits can only be cleared without erasing the UICR */
+                if new & !current != 0 {
+                    anyhow::bail!(
+                        "{} can not be changed from {:#010x} to {:#010x} without erasing the UICR (use the erase command)",
+                        write.description,
+                        current,
+                        new
+                    );
+                }
+                session.core(0)?.write_word_32(NRF_NVMC_CONFIG, 1)?;
+                wait_until(session, NRF_NVMC_READY, |r| r & 1 == 1)?;
+                session.core(0)?.write_word_32(write.address, new)?;
+                wait_until(session, NRF_NVMC_READY, |r| r & 1 == 1)?;
+                session.core(0)?.write_word_32(NRF_NVMC_CONFIG, 0)?;
+            }
+            WriteMethod::Stm32Optr => {
+                let mut core = session.core(0)?;
+                if core.read_word_32(STM32_FLASH_CR)? & (1 << 31) != 0 {
+                    core.write_word_32(STM32_FLASH_KEYR, 0x4567_0123)?;
+                    core.write_word_32(STM32_FLASH_KEYR, 0xCDEF_89AB)?;
+                }
+                if core.read_word_32(STM32_FLASH_CR)? & (1 << 30) != 0 {
+                    core.write_word_32(STM32_FLASH_OPTKEYR, 0x0819_2A3B)?;
+                    core.write_word_32(STM32_FLASH_OPTKEYR, 0x4C5D_6E7F)?;
+                }
+                /* stale errors of earlier operations prevent OPTSTRT, they are cleared by writing ones */
+                core.write_word_32(STM32_FLASH_SR, STM32_FLASH_SR_ERRORS)?;
+                core.write_word_32(STM32_FLASH_OPTR, new)?;
+                let cr = core.read_word_32(STM32_FLASH_CR)?;
+                core.write_word_32(STM32_FLASH_CR, cr | (1 << 17))?;
+                drop(core);
+                wait_until(session, STM32_FLASH_SR, |sr| sr & STM32_FLASH_SR_BSY == 0)?;
+                let sr = session.core(0)?.read_word_32(STM32_FLASH_SR)?;
+                if sr & STM32_FLASH_SR_ERRORS != 0 {
+                    session
+                        .core(0)?
+                        .write_word_32(STM32_FLASH_SR, sr & STM32_FLASH_SR_ERRORS)?;
+                    anyhow::bail!(
+                        "Flash controller rejected the option bytes (FLASH_SR {:#010x})",
+                        sr
+                    );
+                }
+
+                progress_callback("Reloading option bytes...".to_string());
+                let mut core = session.core(0)?;
+                let cr = core.read_word_32(STM32_FLASH_CR)?;
+                /* the target resets right away, so the write may not be acknowledged */
+                let _ = core.write_word_32(STM32_FLASH_CR, cr | (1 << 27));
+                reset = true;
+            }
+        }
+    }
+    Ok(reset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_writes() {
+        let writes: Vec<ConfigWrite> = serde_json::from_str(
+            r#"[
+                {"type": "stm32-option", "field": "iwdg_sw", "value": 0},
+                {"type": "register", "address": "0x40021000", "mask": "0xFF00", "value": "0x1200"},
+                {"type": "stm32-option", "field": "borr_lev", "value": 2}
+            ]"#,
+        )
+        .unwrap();
+        let planned = plan_writes("STM32G071RBTx", &writes).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert_eq!(planned[0].address, 0x4002_1000);
+        assert_eq!((planned[0].mask, planned[0].value), (0xFF00, 0x1200));
+        assert_eq!(planned[1].address, STM32_FLASH_OPTR);
+        assert_eq!((planned[1].mask, planned[1].value), (0x1_1800, 0x1000));
+        assert!(plan_writes("STM32L476RGTx", &writes).is_err());
+        assert!(plan_writes("nRF52840_xxAA", &writes).is_err());
+
+        let rdp2 = ConfigWrite::Stm32Option {
+            field: Some("rdp".to_string()),
+            mask: None,
+            value: 0xCC,
+        };
+        assert!(plan_writes("STM32G474RETx", &[rdp2]).is_err());
+
+        let uicr = vec![
+            ConfigWrite::NrfUicr {
+                register: "approtect".to_string(),
+                value: 0xFFFF_FF5A,
+            },
+            ConfigWrite::NrfUicr {
+                register: "customer3".to_string(),
+                value: 0x1234,
+            },
+        ];
+        let planned = plan_writes("nRF52840_xxAA", &uicr).unwrap();
+        assert_eq!(planned[0].address, 0x1000_1208);
+        assert_eq!(planned[1].address, 0x1000_108C);
+        assert!(plan_writes("STM32G071RBTx", &uicr).is_err());
+    }
+}
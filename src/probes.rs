@@ -0,0 +1,373 @@
+use crate::{get_probes, utils};
+use anyhow::{self, Context};
+use probe_rs::probe::{DebugProbeInfo, DebugProbeSelector, Probe};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Selects a debug probe either by its serial number alone or by the
+/// `VID:PID[:Serial]` triplet printed by the `probes` command, `all` selects
+/// every connected probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeSelector {
+    All,
+    Serial(String),
+    Usb(DebugProbeSelector),
+}
+
+impl ProbeSelector {
+    pub fn matches(&self, probe: &DebugProbeInfo) -> bool {
+        match self {
+            ProbeSelector::All => true,
+            ProbeSelector::Serial(serial) => probe.serial_number.as_ref() == Some(serial),
+            ProbeSelector::Usb(selector) => selector.matches_probe(probe),
+        }
+    }
+}
+
+/// Selector of all connected probes, it can not be used as an alias.
+const ALL_PROBES: &str = "all";
+
+impl FromStr for ProbeSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            anyhow::bail!("Probe selector cannot be empty");
+        }
+        if s == ALL_PROBES {
+            return Ok(ProbeSelector::All);
+        }
+        if !s.contains(':') {
+            return Ok(ProbeSelector::Serial(s.to_owned()));
+        }
+        DebugProbeSelector::from_str(s)
+            .map(ProbeSelector::Usb)
+            .context(format!(
+                "Invalid probe selector \"{}\", expected VID:PID, VID:PID:Serial or Serial",
+                s
+            ))
+    }
+}
+
+impl fmt::Display for ProbeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeSelector::All => write!(f, "{}", ALL_PROBES),
+            ProbeSelector::Serial(serial) => write!(f, "{}", serial),
+            ProbeSelector::Usb(selector) => write!(f, "{}", selector),
+        }
+    }
+}
+
+/// Formats the probe as the `VID:PID[:Serial]` selector accepted by [`ProbeSelector`].
+pub fn probe_selector_string(probe: &DebugProbeInfo) -> String {
+    match probe.serial_number {
+        Some(ref serial) if !serial.is_empty() => {
+            format!(
+                "{:04X}:{:04X}:{}",
+                probe.vendor_id, probe.product_id, serial
+            )
+        }
+        _ => format!("{:04X}:{:04X}", probe.vendor_id, probe.product_id),
+    }
+}
+
+/// Description of a connected probe for machine-readable listings.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProbeSummary {
+    /// Selector of the probe as accepted by `--probe`
+    pub selector: String,
+    pub identifier: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub alias: Option<String>,
+}
+
+impl ProbeSummary {
+    pub fn new(probe: &DebugProbeInfo, aliases: &ProbeAliases) -> Self {
+        ProbeSummary {
+            selector: probe_selector_string(probe),
+            identifier: probe.identifier.clone(),
+            vendor_id: probe.vendor_id,
+            product_id: probe.product_id,
+            serial_number: probe.serial_number.clone().filter(|s| !s.is_empty()),
+            alias: aliases.alias_of(probe).map(str::to_owned),
+        }
+    }
+}
+
+/// Friendly names of probes, persisted as a map of alias to probe serial number.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct ProbeAliases {
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+}
+
+impl ProbeAliases {
+    pub fn read_from_path(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path).context(format!(
+            "Failed to read probe aliases file {}",
+            path.display()
+        ))?;
+        toml::from_str(&contents).context(format!(
+            "Failed to parse probe aliases file {}",
+            path.display()
+        ))
+    }
+
+    pub fn write_to_path(&self, path: &Path) -> anyhow::Result<()> {
+        let contents = toml::to_string(self)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Assigns `alias` to the probe with `serial_number`, replacing any
+    /// previous alias of that probe.
+    pub fn set(&mut self, alias: &str, serial_number: &str) -> anyhow::Result<()> {
+        if alias.is_empty() || alias.contains([':', ',']) {
+            anyhow::bail!("Probe alias cannot be empty or contain ':' or ','");
+        }
+        if alias == ALL_PROBES {
+            anyhow::bail!(
+                "Probe alias cannot be \"{}\", it selects all probes",
+                ALL_PROBES
+            );
+        }
+        self.aliases.retain(|_, serial| serial != serial_number);
+        self.aliases
+            .insert(alias.to_owned(), serial_number.to_owned());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, alias: &str) -> anyhow::Result<()> {
+        self.aliases
+            .remove(alias)
+            .map(|_| ())
+            .context(format!("Probe alias \"{}\" not found", alias))
+    }
+
+    pub fn alias_of(&self, probe: &DebugProbeInfo) -> Option<&str> {
+        let serial_number = probe.serial_number.as_ref()?;
+        self.aliases
+            .iter()
+            .find(|(_, serial)| *serial == serial_number)
+            .map(|(alias, _)| alias.as_str())
+    }
+
+    /// Translates a selector naming an alias into a serial number selector.
+    pub fn resolve(&self, selector: &ProbeSelector) -> ProbeSelector {
+        match selector {
+            ProbeSelector::Serial(s) => match self.aliases.get(s) {
+                Some(serial) => ProbeSelector::Serial(serial.clone()),
+                None => selector.clone(),
+            },
+            _ => selector.clone(),
+        }
+    }
+}
+
+/// Short name of the probe, its alias or its selector.
+pub fn probe_label(probe: &DebugProbeInfo, aliases: &ProbeAliases) -> String {
+    aliases
+        .alias_of(probe)
+        .map_or_else(|| probe_selector_string(probe), str::to_owned)
+}
+
+/// Describes the probe as its selector, name and alias (if any).
+pub fn describe_probe(probe: &DebugProbeInfo, aliases: &ProbeAliases) -> String {
+    match aliases.alias_of(probe) {
+        Some(alias) => format!(
+            "{} ({}) [{}]",
+            probe_selector_string(probe),
+            probe.identifier,
+            alias
+        ),
+        None => format!("{} ({})", probe_selector_string(probe), probe.identifier),
+    }
+}
+
+/// Returns all probes matching `selector` (or all probes if there is no
+/// selector), failing if there are none.
+pub fn matching_probes<'a>(
+    probes: &'a [DebugProbeInfo],
+    selector: Option<&ProbeSelector>,
+    aliases: &ProbeAliases,
+) -> anyhow::Result<Vec<&'a DebugProbeInfo>> {
+    let Some(selector) = selector else {
+        if probes.is_empty() {
+            anyhow::bail!("No debug probes found");
+        }
+        return Ok(probes.iter().collect());
+    };
+
+    let resolved = aliases.resolve(selector);
+    let matching = probes
+        .iter()
+        .filter(|p| resolved.matches(p))
+        .collect::<Vec<&DebugProbeInfo>>();
+    if matching.is_empty() {
+        anyhow::bail!(
+            "No debug probe matches \"{}\" (you can use the probes command to list all probes)",
+            selector
+        );
+    }
+    Ok(matching)
+}
+
+/// Picks the single probe matching `selector`.
+///
+/// When more probes match and stdin is a terminal, the user is asked to
+/// choose one of them, otherwise the selection fails as ambiguous.
+pub fn select_probe<'a>(
+    probes: &'a [DebugProbeInfo],
+    selector: Option<&ProbeSelector>,
+    aliases: &ProbeAliases,
+) -> anyhow::Result<&'a DebugProbeInfo> {
+    let matching = matching_probes(probes, selector, aliases)?;
+    match matching[..] {
+        [probe] => Ok(probe),
+        _ if std::io::stdin().is_terminal() => pick_probe_from_command_line(&matching, aliases),
+        _ => anyhow::bail!(
+            "Multiple debug probes match{}, please select one using --probe: {}",
+            selector.map_or(String::new(), |s| format!(" \"{}\"", s)),
+            matching
+                .iter()
+                .map(|p| describe_probe(p, aliases))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Picks the probes for a command operating on multiple probes at once.
+///
+/// The `all` selector adds every connected probe, any other selector must
+/// pick a single probe as in [`select_probe`].
+pub fn select_probes<'a>(
+    probes: &'a [DebugProbeInfo],
+    selectors: &[ProbeSelector],
+    aliases: &ProbeAliases,
+) -> anyhow::Result<Vec<&'a DebugProbeInfo>> {
+    if selectors.is_empty() {
+        return Ok(vec![select_probe(probes, None, aliases)?]);
+    }
+
+    let mut selected = Vec::<&DebugProbeInfo>::new();
+    for selector in selectors {
+        let matching = match selector {
+            ProbeSelector::All => matching_probes(probes, Some(selector), aliases)?,
+            _ => vec![select_probe(probes, Some(selector), aliases)?],
+        };
+        for probe in matching {
+            if !selected.iter().any(|p| std::ptr::eq(*p, probe)) {
+                selected.push(probe);
+            }
+        }
+    }
+    Ok(selected)
+}
+
+pub fn pick_probe_from_command_line<'a>(
+    probes: &[&'a DebugProbeInfo],
+    aliases: &ProbeAliases,
+) -> anyhow::Result<&'a DebugProbeInfo> {
+    eprintln!("Multiple debug probes found, select one:");
+    for (i, probe) in probes.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, describe_probe(probe, aliases));
+    }
+    loop {
+        eprint!("Probe number [1-{}]: ", probes.len());
+        let line = utils::read_line().context("Failed to read probe selection")?;
+        match line.parse::<usize>() {
+            Ok(i) if (1..=probes.len()).contains(&i) => return Ok(probes[i - 1]),
+            _ => eprintln!("Invalid probe number \"{}\"", line),
+        }
+    }
+}
+
+/// Lists the connected probes and opens the one picked by [`select_probe`].
+pub fn open_probe(
+    selector: Option<&ProbeSelector>,
+    aliases: &ProbeAliases,
+) -> anyhow::Result<Probe> {
+    let probes = get_probes()?;
+    select_probe(&probes, selector, aliases)?
+        .open()
+        .context("Failed to open probe")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_selector_parsing() {
+        assert_eq!(
+            "0001A2B3".parse::<ProbeSelector>().unwrap(),
+            ProbeSelector::Serial("0001A2B3".to_string())
+        );
+
+        let ProbeSelector::Usb(selector) = "1366:1015".parse::<ProbeSelector>().unwrap() else {
+            panic!("Expected a VID:PID selector");
+        };
+        assert_eq!((selector.vendor_id, selector.product_id), (0x1366, 0x1015));
+        assert_eq!(selector.serial_number, None);
+
+        let ProbeSelector::Usb(selector) = "0483:374B:066DFF48".parse::<ProbeSelector>().unwrap()
+        else {
+            panic!("Expected a VID:PID:Serial selector");
+        };
+        assert_eq!((selector.vendor_id, selector.product_id), (0x0483, 0x374B));
+        assert_eq!(selector.serial_number.as_deref(), Some("066DFF48"));
+
+        assert_eq!("all".parse::<ProbeSelector>().unwrap(), ProbeSelector::All);
+        assert!("".parse::<ProbeSelector>().is_err());
+        assert!("xyz:1015".parse::<ProbeSelector>().is_err());
+        assert!(matching_probes(&[], None, &ProbeAliases::default()).is_err());
+    }
+
+    #[test]
+    fn test_probe_aliases() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("probe-aliases.toml");
+
+        let mut aliases = ProbeAliases::read_from_path(&path).unwrap();
+        assert_eq!(aliases, ProbeAliases::default());
+
+        aliases.set("rig-3-left", "066DFF48").unwrap();
+        aliases.set("rig-3-right", "0001A2B3").unwrap();
+        assert!(aliases.set("rig:4", "0001A2B3").is_err());
+        assert!(aliases.set("rig,4", "0001A2B3").is_err());
+        assert!(aliases.set("all", "0001A2B3").is_err());
+        aliases.write_to_path(&path).unwrap();
+
+        let mut aliases = ProbeAliases::read_from_path(&path).unwrap();
+        assert_eq!(
+            aliases.resolve(&ProbeSelector::Serial("rig-3-left".to_string())),
+            ProbeSelector::Serial("066DFF48".to_string())
+        );
+        assert_eq!(
+            aliases.resolve(&ProbeSelector::Serial("0001A2B3".to_string())),
+            ProbeSelector::Serial("0001A2B3".to_string())
+        );
+
+        /* re-aliasing a probe drops its previous alias */
+        aliases.set("rig-4", "066DFF48").unwrap();
+        assert_eq!(
+            aliases.resolve(&ProbeSelector::Serial("rig-3-left".to_string())),
+            ProbeSelector::Serial("rig-3-left".to_string())
+        );
+
+        aliases.remove("rig-4").unwrap();
+        assert!(aliases.remove("rig-4").is_err());
+    }
+}
@@ -0,0 +1,589 @@
+use crate::storage::Firmware;
+use crate::utils;
+use anyhow::{self, Context};
+use chrono::Utc;
+use object::{Object, ObjectSection, ObjectSymbol};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// How a provisioned value is turned into the bytes written to the image.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Little endian integer
+    Le,
+    /// Big endian integer
+    Be,
+    /// Text, padded with zeros to the patch size
+    Ascii,
+    /// Hex string such as a MAC address or a key, separators `:` and `-` are ignored
+    Hex,
+}
+
+/// Where the values of a patch point come from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ValueSource {
+    /// Increments from `start`, continuing after the highest value assigned so far
+    Counter { start: u64 },
+    /// Takes the next unused row of the CSV file given on the command line
+    Csv { column: String },
+    /// Substitutes `{name}` with the values of the preceding patch points and
+    /// `{device_id}` with the unique ID of the device
+    Template { template: String },
+}
+
+/// A place in the firmware image which receives a unique value for every device.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PatchPoint {
+    pub name: String,
+    /// ELF symbol to patch, takes precedence over `address`
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default, deserialize_with = "utils::deserialize_optional_number")]
+    pub address: Option<u64>,
+    pub size: usize,
+    pub encoding: Encoding,
+    pub source: ValueSource,
+}
+
+impl PatchPoint {
+    pub fn encode(&self, value: &str) -> anyhow::Result<Vec<u8>> {
+        let bytes = match self.encoding {
+            Encoding::Le | Encoding::Be => {
+                let n = utils::parse_number(value)
+                    .context(format!("Value \"{}\" is not a number", value))?;
+                if self.size < 8 && n >> (self.size * 8) != 0 {
+                    anyhow::bail!("Value {} does not fit into {} bytes", n, self.size);
+                }
+                let mut bytes = n.to_le_bytes().to_vec();
+                bytes.resize(self.size, 0);
+                if self.encoding == Encoding::Be {
+                    bytes.reverse();
+                }
+                bytes
+            }
+            Encoding::Ascii => {
+                if value.len() > self.size {
+                    anyhow::bail!("Value \"{}\" is longer than {} bytes", value, self.size);
+                }
+                let mut bytes = value.as_bytes().to_vec();
+                bytes.resize(self.size, 0);
+                bytes
+            }
+            Encoding::Hex => {
+                let digits = value.replace([':', '-'], "");
+                if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    anyhow::bail!("Value \"{}\" is not a hex string", value);
+                }
+                if digits.len() != self.size * 2 {
+                    anyhow::bail!("Value \"{}\" is not {} bytes long", value, self.size);
+                }
+                (0..digits.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()?
+            }
+        };
+        Ok(bytes)
+    }
+}
+
+/// Values assigned to a single device.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProvisioningRecord {
+    pub timestamp: i64,
+    pub firmware_name: String,
+    pub firmware_version: String,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    pub values: BTreeMap<String, String>,
+}
+
+/// Assigns unique values to the patch points of a firmware and keeps a
+/// ledger of all values assigned so far, so no value is ever used twice.
+///
+/// Values are reserved by [`Provisioner::allocate`] and only written to the
+/// ledger by [`Provisioner::commit`] once the device has been programmed.
+#[derive(Debug)]
+pub struct Provisioner {
+    ledger_path: PathBuf,
+    csv: Vec<BTreeMap<String, String>>,
+    reserved: Mutex<HashSet<(String, String)>>,
+}
+
+impl Provisioner {
+    pub fn new(ledger_path: PathBuf, csv_path: Option<&Path>) -> anyhow::Result<Self> {
+        let csv = match csv_path {
+            Some(path) => read_csv(
+                &fs::read_to_string(path)
+                    .context(format!("Failed to read CSV file {}", path.display()))?,
+            )
+            .context(format!("Failed to parse CSV file {}", path.display()))?,
+            None => vec![],
+        };
+        Ok(Provisioner {
+            ledger_path,
+            csv,
+            reserved: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn read_ledger(&self) -> anyhow::Result<Vec<ProvisioningRecord>> {
+        if !self.ledger_path.exists() {
+            return Ok(vec![]);
+        }
+
+        fs::read_to_string(&self.ledger_path)
+            .context("Failed to read provisioning ledger")?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).context("Failed to parse provisioning record"))
+            .collect()
+    }
+
+    /// Picks and reserves the values for the next device.
+    pub fn allocate(
+        &self,
+        firmware: &Firmware,
+        device_id: Option<&str>,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut reserved = self.reserved.lock().unwrap();
+
+        /* values used by earlier devices, whatever their firmware, or reserved for
+         * devices being flashed right now */
+        let mut used = self
+            .read_ledger()?
+            .into_iter()
+            .flat_map(|r| r.values)
+            .collect::<HashSet<(String, String)>>();
+        used.extend(reserved.iter().cloned());
+        let is_used = |name: &str, value: &str| used.contains(&(name.to_owned(), value.to_owned()));
+
+        let csv_columns = firmware
+            .provisioning
+            .iter()
+            .filter_map(|p| match p.source {
+                ValueSource::Csv { ref column } => Some((p.name.as_str(), column.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<(&str, &str)>>();
+        let csv_row = match csv_columns.is_empty() {
+            true => None,
+            false => Some(
+                self.csv
+                    .iter()
+                    .find(|row| {
+                        csv_columns.iter().all(|(name, column)| {
+                            row.get(*column).is_some_and(|v| !is_used(name, v))
+                        })
+                    })
+                    .context("No unused rows left in the provisioning CSV file")?,
+            ),
+        };
+
+        let mut values = BTreeMap::<String, String>::new();
+        for point in &firmware.provisioning {
+            let value = match point.source {
+                ValueSource::Counter { start } => {
+                    let highest = used
+                        .iter()
+                        .filter(|(name, _)| *name == point.name)
+                        .filter_map(|(_, v)| utils::parse_number(v).ok())
+                        .max();
+                    match highest {
+                        None => start,
+                        Some(n) => n
+                            .checked_add(1)
+                            .context(format!("Counter \"{}\" is exhausted", point.name))?
+                            .max(start),
+                    }
+                    .to_string()
+                }
+                ValueSource::Csv { ref column } => csv_row
+                    .and_then(|row| row.get(column))
+                    .cloned()
+                    .unwrap_or_default(),
+                ValueSource::Template { ref template } => {
+                    let mut value = template.clone();
+                    if value.contains("{device_id}") {
+                        let device_id = device_id.context(format!(
+                            "\"{}\" uses the device ID, but it can not be read from this chip",
+                            point.name
+                        ))?;
+                        value = value.replace("{device_id}", device_id);
+                    }
+                    for (name, v) in &values {
+                        value = value.replace(&format!("{{{}}}", name), v);
+                    }
+                    value
+                }
+            };
+            if is_used(&point.name, &value) {
+                anyhow::bail!(
+                    "Value \"{}\" of \"{}\" has already been assigned to another device",
+                    value,
+                    point.name
+                );
+            }
+            values.insert(point.name.clone(), value);
+        }
+
+        reserved.extend(values.clone());
+        Ok(values)
+    }
+
+    /// Records the values as assigned to a programmed device.
+    pub fn commit(
+        &self,
+        firmware: &Firmware,
+        device_id: Option<&str>,
+        values: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut reserved = self.reserved.lock().unwrap();
+        let record = ProvisioningRecord {
+            timestamp: Utc::now().timestamp(),
+            firmware_name: firmware.name.clone(),
+            firmware_version: firmware.version.clone(),
+            device_id: device_id.map(str::to_owned),
+            values: values.clone(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.ledger_path)
+            .context("Failed to open provisioning ledger")?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)
+            .context("Failed to write to provisioning ledger")?;
+        for value in values.clone() {
+            reserved.remove(&value);
+        }
+        Ok(())
+    }
+
+    /// Gives back values reserved for a device which failed to program.
+    pub fn release(&self, values: &BTreeMap<String, String>) {
+        let mut reserved = self.reserved.lock().unwrap();
+        for value in values.clone() {
+            reserved.remove(&value);
+        }
+    }
+}
+
+/// Writes the encoded values into the patch points of an ELF image.
+pub fn patch_image(
+    image: &mut [u8],
+    points: &[PatchPoint],
+    values: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut patches = Vec::<(usize, Vec<u8>)>::new();
+    {
+        let file = object::File::parse(&*image).context("Failed to parse firmware ELF file")?;
+        for point in points {
+            let value = values
+                .get(&point.name)
+                .context(format!("No value assigned to \"{}\"", point.name))?;
+            let bytes = point
+                .encode(value)
+                .context(format!("Failed to encode the value of \"{}\"", point.name))?;
+
+            let (section, address) = match (&point.symbol, point.address) {
+                (Some(name), _) => {
+                    let symbol = file
+                        .symbol_by_name(name)
+                        .context(format!("Symbol \"{}\" not found in the firmware", name))?;
+                    let section = symbol
+                        .section_index()
+                        .and_then(|i| file.section_by_index(i).ok())
+                        .context(format!("Symbol \"{}\" is not defined in a section", name))?;
+                    (section, symbol.address())
+                }
+                (None, Some(address)) => (
+                    file.sections()
+                        .find(|s| {
+                            s.file_range().is_some()
+                                && (s.address()..s.address() + s.size()).contains(&address)
+                        })
+                        .context(format!(
+                            "Address {:#010x} is not part of the firmware image",
+                            address
+                        ))?,
+                    address,
+                ),
+                (None, None) => anyhow::bail!(
+                    "Patch point \"{}\" needs either a symbol or an address",
+                    point.name
+                ),
+            };
+
+            let (offset, size) = section.file_range().context(format!(
+                "Patch point \"{}\" is in a section without data",
+                point.name
+            ))?;
+            let start = address - section.address();
+            if start + bytes.len() as u64 > size {
+                anyhow::bail!(
+                    "Patch point \"{}\" does not fit into its section",
+                    point.name
+                );
+            }
+            patches.push(((offset + start) as usize, bytes));
+        }
+    }
+
+    for (offset, bytes) in patches {
+        image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok(())
+}
+
+fn read_csv(contents: &str) -> anyhow::Result<Vec<BTreeMap<String, String>>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(contents.as_bytes());
+    let header = reader
+        .headers()
+        .context("Failed to read CSV header")?
+        .clone();
+    reader
+        .records()
+        .map(|record| {
+            let record = record.context("Failed to read CSV row")?;
+            Ok(header
+                .iter()
+                .map(str::to_owned)
+                .zip(record.iter().map(str::to_owned))
+                .collect())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::write;
+    use tempfile::tempdir;
+
+    fn point(name: &str, encoding: Encoding, size: usize, source: ValueSource) -> PatchPoint {
+        PatchPoint {
+            name: name.to_string(),
+            symbol: Some(name.to_uppercase()),
+            address: None,
+            size,
+            encoding,
+            source,
+        }
+    }
+
+    fn firmware(provisioning: Vec<PatchPoint>) -> Firmware {
+        Firmware {
+            name: "blinky".to_string(),
+            version: "fast".to_string(),
+            chip: "STM32L053R8Tx".to_string(),
+            compatible_chips: vec![],
+            path: PathBuf::new(),
+            unique_id: None,
+            provisioning,
+            option_bytes: vec![],
+            target_description: None,
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let counter = ValueSource::Counter { start: 0 };
+        assert_eq!(
+            point("serial", Encoding::Le, 4, counter.clone())
+                .encode("0x1234")
+                .unwrap(),
+            vec![0x34, 0x12, 0, 0]
+        );
+        assert_eq!(
+            point("serial", Encoding::Be, 2, counter.clone())
+                .encode("4660")
+                .unwrap(),
+            vec![0x12, 0x34]
+        );
+        assert!(point("serial", Encoding::Le, 1, counter.clone())
+            .encode("256")
+            .is_err());
+        assert_eq!(
+            point("label", Encoding::Ascii, 4, counter.clone())
+                .encode("AB")
+                .unwrap(),
+            b"AB\0\0".to_vec()
+        );
+        assert_eq!(
+            point("mac", Encoding::Hex, 6, counter.clone())
+                .encode("02:00:5E:10:00:01")
+                .unwrap(),
+            vec![0x02, 0x00, 0x5E, 0x10, 0x00, 0x01]
+        );
+        assert!(point("mac", Encoding::Hex, 6, counter.clone())
+            .encode("02:00:5E")
+            .is_err());
+        assert!(point("mac", Encoding::Hex, 2, counter)
+            .encode("0é1")
+            .is_err());
+    }
+
+    #[test]
+    fn test_allocation() {
+        let temp_dir = tempdir().unwrap();
+        let csv_path = temp_dir.path().join("macs.csv");
+        fs::write(
+            &csv_path,
+            "mac,key\n02:00:00:00:00:01,aa\n02:00:00:00:00:02,bb\n",
+        )
+        .unwrap();
+
+        let provisioner =
+            Provisioner::new(temp_dir.path().join("ledger.jsonl"), Some(&csv_path)).unwrap();
+        let firmware = firmware(vec![
+            point(
+                "serial",
+                Encoding::Le,
+                4,
+                ValueSource::Counter { start: 100 },
+            ),
+            point(
+                "mac",
+                Encoding::Hex,
+                6,
+                ValueSource::Csv {
+                    column: "mac".to_string(),
+                },
+            ),
+            point(
+                "label",
+                Encoding::Ascii,
+                32,
+                ValueSource::Template {
+                    template: "SN{serial}-{device_id}".to_string(),
+                },
+            ),
+        ]);
+
+        let first = provisioner.allocate(&firmware, Some("ABCD")).unwrap();
+        assert_eq!(first["serial"], "100");
+        assert_eq!(first["mac"], "02:00:00:00:00:01");
+        assert_eq!(first["label"], "SN100-ABCD");
+
+        /* reserved values are not handed out twice */
+        let second = provisioner.allocate(&firmware, Some("EF01")).unwrap();
+        assert_eq!(second["serial"], "101");
+        assert_eq!(second["mac"], "02:00:00:00:00:02");
+        provisioner.release(&second);
+
+        provisioner.commit(&firmware, Some("ABCD"), &first).unwrap();
+        assert_eq!(provisioner.read_ledger().unwrap().len(), 1);
+
+        /* a fresh session continues from the ledger */
+        let provisioner =
+            Provisioner::new(temp_dir.path().join("ledger.jsonl"), Some(&csv_path)).unwrap();
+        let third = provisioner.allocate(&firmware, Some("ABCD")).unwrap();
+        assert_eq!(third["serial"], "101");
+        assert_eq!(third["mac"], "02:00:00:00:00:02");
+
+        /* the label of a device with the same ID would be reused */
+        provisioner.release(&third);
+        let constant = firmware_with_constant_label();
+        let values = provisioner.allocate(&constant, None).unwrap();
+        provisioner.commit(&constant, None, &values).unwrap();
+        assert!(provisioner.allocate(&constant, None).is_err());
+        let renamed = Firmware {
+            name: "blinky-v2".to_string(),
+            ..constant
+        };
+        assert!(provisioner.allocate(&renamed, None).is_err());
+
+        /* the device ID can not be left out of a template */
+        assert!(provisioner.allocate(&firmware, None).is_err());
+
+        /* all CSV rows are used up */
+        provisioner.commit(&firmware, None, &third).unwrap();
+        assert!(provisioner.allocate(&firmware, Some("ABCD")).is_err());
+
+        /* counters do not wrap around */
+        let counter = Firmware {
+            provisioning: vec![point(
+                "serial",
+                Encoding::Le,
+                8,
+                ValueSource::Counter { start: 0 },
+            )],
+            ..firmware
+        };
+        let values = BTreeMap::from([("serial".to_string(), u64::MAX.to_string())]);
+        provisioner.commit(&counter, None, &values).unwrap();
+        assert!(provisioner.allocate(&counter, None).is_err());
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let rows = read_csv("name, key\n\n\"Doe, John\",\"a\"\"b\"\n").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["name"], "Doe, John");
+        assert_eq!(rows[0]["key"], "a\"b");
+        assert!(read_csv("name,key\nJohn,aa,extra\n").is_err());
+        assert!(read_csv("name,key\nJohn, \"a,b\"\n").is_err());
+    }
+
+    fn firmware_with_constant_label() -> Firmware {
+        firmware(vec![point(
+            "label",
+            Encoding::Ascii,
+            8,
+            ValueSource::Template {
+                template: "fixed".to_string(),
+            },
+        )])
+    }
+
+    #[test]
+    fn test_patch_image() {
+        let mut obj = write::Object::new(
+            object::BinaryFormat::Elf,
+            object::Architecture::Arm,
+            object::Endianness::Little,
+        );
+        let section = obj.add_section(vec![], b".data".to_vec(), object::SectionKind::Data);
+        obj.append_section_data(section, &[0xFF; 16], 4);
+        obj.add_symbol(write::Symbol {
+            name: b"SERIAL".to_vec(),
+            value: 4,
+            size: 4,
+            kind: object::SymbolKind::Data,
+            scope: object::SymbolScope::Linkage,
+            weak: false,
+            section: write::SymbolSection::Section(section),
+            flags: object::SymbolFlags::None,
+        });
+        let mut image = obj.write().unwrap();
+
+        let points = vec![point(
+            "serial",
+            Encoding::Le,
+            4,
+            ValueSource::Counter { start: 0 },
+        )];
+        let values = BTreeMap::from([("serial".to_string(), "0x01020304".to_string())]);
+        patch_image(&mut image, &points, &values).unwrap();
+
+        let file = object::File::parse(&*image).unwrap();
+        let data = file.section_by_name(".data").unwrap().data().unwrap();
+        assert_eq!(data[..8], [0xFF, 0xFF, 0xFF, 0xFF, 0x04, 0x03, 0x02, 0x01]);
+
+        let missing = vec![point(
+            "key",
+            Encoding::Hex,
+            4,
+            ValueSource::Counter { start: 0 },
+        )];
+        assert!(patch_image(&mut image, &missing, &values).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::monitor::ChannelDecoder;
+use probe_rs::semihosting::SemihostingCommand;
+use probe_rs::{BreakpointCause, Core, CoreStatus, HaltReason};
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// Options of [`crate::run_firmware`].
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Give up if the firmware does not exit within this time
+    pub timeout: Duration,
+    /// Also stream the RTT channels of the firmware
+    pub rtt: bool,
+}
+
+/// How a firmware run by [`crate::run_firmware`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The firmware exited through semihosting with this status.
+    Exited(i32),
+    /// The firmware did not exit before the timeout.
+    TimedOut,
+}
+
+/// Name under which semihosting console output is passed to the output callback.
+pub const SEMIHOSTING_CHANNEL: &str = "semihosting";
+
+/// Handles of the `:tt` console streams opened by the target.
+const TT_HANDLES: [u32; 3] = [1, 2, 3];
+
+/// Answers a semihosting request of the halted target, returns the exit
+/// status when the firmware asked to exit.
+///
+/// Console output is fed into `console` and every completed line passed
+/// to `line_callback`. Only console streams are supported, opening any
+/// other file fails the run.
+pub fn handle_semihosting(
+    core: &mut Core,
+    command: SemihostingCommand,
+    console: &mut ChannelDecoder,
+    line_callback: &mut dyn FnMut(&str, String),
+) -> anyhow::Result<Option<i32>> {
+    let mut output = |data: &[u8]| {
+        for line in console.push(data) {
+            line_callback(SEMIHOSTING_CHANNEL, line);
+        }
+    };
+
+    match command {
+        SemihostingCommand::ExitSuccess => return Ok(Some(0)),
+        SemihostingCommand::ExitError(details) => {
+            return Ok(Some(details.exit_status.map_or(1, |s| s as i32)));
+        }
+        SemihostingCommand::WriteConsole(request) => output(request.read(core)?.as_bytes()),
+        SemihostingCommand::Open(request) => {
+            let path = request.path(core)?;
+            if path != ":tt" {
+                anyhow::bail!("Firmware tried to open \"{}\" through semihosting", path);
+            }
+            let handle = match request.mode().chars().next() {
+                Some('r') => TT_HANDLES[0],
+                Some('w') => TT_HANDLES[1],
+                _ => TT_HANDLES[2],
+            };
+            request.respond_with_handle(core, NonZeroU32::new(handle).unwrap())?;
+        }
+        SemihostingCommand::Write(request) if TT_HANDLES.contains(&request.file_handle()) => {
+            output(&request.read(core)?);
+            request.write_status(core, 0)?;
+        }
+        SemihostingCommand::Close(request) if TT_HANDLES.contains(&request.file_handle()) => {
+            request.success(core)?;
+        }
+        SemihostingCommand::GetCommandLine(request) => {
+            request.write_command_line_to_target(core, "")?;
+        }
+        SemihostingCommand::Time(request) => request.write_current_time(core)?,
+        SemihostingCommand::Errno(request) => request.write_errno(core, 0)?,
+        other => anyhow::bail!("Unsupported semihosting request {:?}", other),
+    }
+    Ok(None)
+}
+
+/// Checks whether the target halted for a reason the run can continue from,
+/// returning the pending semihosting request if there is one.
+pub fn check_status(status: CoreStatus) -> anyhow::Result<Option<SemihostingCommand>> {
+    match status {
+        CoreStatus::Halted(HaltReason::Breakpoint(BreakpointCause::Semihosting(command))) => {
+            Ok(Some(command))
+        }
+        CoreStatus::Halted(HaltReason::Exception) => {
+            anyhow::bail!("Firmware crashed with a HardFault")
+        }
+        CoreStatus::Halted(reason) => anyhow::bail!("Firmware halted unexpectedly ({:?})", reason),
+        CoreStatus::LockedUp => anyhow::bail!("Firmware locked up the core"),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_status() {
+        assert_eq!(check_status(CoreStatus::Running).unwrap(), None);
+        assert_eq!(check_status(CoreStatus::Sleeping).unwrap(), None);
+        assert_eq!(
+            check_status(CoreStatus::Halted(HaltReason::Breakpoint(
+                BreakpointCause::Semihosting(SemihostingCommand::ExitSuccess)
+            )))
+            .unwrap(),
+            Some(SemihostingCommand::ExitSuccess)
+        );
+        assert!(check_status(CoreStatus::Halted(HaltReason::Exception)).is_err());
+        assert!(check_status(CoreStatus::LockedUp).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{self, Context};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Environment variable with the passphrase of the encrypted store and of
+/// protected tokens, it is asked for interactively when not set.
+pub const PASSPHRASE_ENV: &str = "QUICK_FLASH_PASSPHRASE";
+
+/// Extension of the encrypted secret files, they are named after the
+/// credentials file they belong to.
+pub const SECRET_EXTENSION: &str = "secret";
+
+/// Service under which the secret keys are saved in the OS keyring.
+const KEYRING_SERVICE: &str = "quick-flash";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Where the secret key of saved credentials is kept.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretStore {
+    /// In the credentials file itself
+    #[default]
+    Plaintext,
+    /// In the OS keyring, e.g. the Secret Service on Linux
+    Keyring,
+    /// In a file next to the credentials file, encrypted with a passphrase
+    Encrypted,
+}
+
+impl FromStr for SecretStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plaintext" => Ok(SecretStore::Plaintext),
+            "keyring" => Ok(SecretStore::Keyring),
+            "encrypted" => Ok(SecretStore::Encrypted),
+            _ => anyhow::bail!(
+                "Invalid secret store \"{}\", expected plaintext, keyring or encrypted",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SecretStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretStore::Plaintext => write!(f, "plaintext"),
+            SecretStore::Keyring => write!(f, "keyring"),
+            SecretStore::Encrypted => write!(f, "encrypted"),
+        }
+    }
+}
+
+/// Keyring entry of the secret key with the given `id`.
+pub fn keyring_entry(id: &str) -> anyhow::Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, id).context("Failed to access the keyring")
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> anyhow::Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive the encryption key ({})", e))?;
+    XChaCha20Poly1305::new_from_slice(&key).context("Invalid encryption key")
+}
+
+/// Encrypts `secret` with a key derived from `passphrase`, the result holds
+/// the salt and nonce followed by the ciphertext.
+pub fn encrypt(secret: &str, passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let mut header = [0u8; SALT_LEN + NONCE_LEN];
+    getrandom::fill(&mut header)
+        .map_err(|e| anyhow::anyhow!("Failed to generate random data ({})", e))?;
+    let (salt, nonce) = header.split_at(SALT_LEN);
+    let nonce = XNonce::try_from(nonce).context("Invalid nonce")?;
+    let ciphertext = cipher(passphrase, salt)?
+        .encrypt(&nonce, secret.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the secret"))?;
+    Ok([&header[..], &ciphertext].concat())
+}
+
+/// Decrypts data produced by [`encrypt`].
+pub fn decrypt(data: &[u8], passphrase: &str) -> anyhow::Result<String> {
+    if data.len() < SALT_LEN + NONCE_LEN {
+        anyhow::bail!("Encrypted secret is truncated");
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let nonce = XNonce::try_from(nonce).context("Invalid nonce")?;
+    let secret = cipher(passphrase, salt)?
+        .decrypt(&nonce, ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt the secret, is the passphrase right?"))?;
+    String::from_utf8(secret).context("Decrypted secret is not valid text")
+}
+
+/// Takes the passphrase from [`PASSPHRASE_ENV`], or asks for it without
+/// echoing it. A `new` passphrase is asked for twice.
+pub fn read_passphrase(prompt: &str, new: bool) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let read = |prompt: &str| {
+        rpassword::prompt_password(prompt).context(format!(
+            "Failed to read the passphrase (it can also be set with {})",
+            PASSPHRASE_ENV
+        ))
+    };
+    let passphrase = read(prompt)?;
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase cannot be empty");
+    }
+    if new && read("Repeat the passphrase: ")? != passphrase {
+        anyhow::bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption() {
+        let data = encrypt("secret_key", "correct horse").unwrap();
+        assert!(!data.windows(10).any(|w| w == b"secret_key"));
+        assert_eq!(decrypt(&data, "correct horse").unwrap(), "secret_key");
+        assert!(decrypt(&data, "wrong horse").is_err());
+        assert!(decrypt(&data[..20], "correct horse").is_err());
+        assert_ne!(encrypt("secret_key", "correct horse").unwrap(), data);
+
+        assert_eq!(
+            "keyring".parse::<SecretStore>().unwrap(),
+            SecretStore::Keyring
+        );
+        assert!("vault".parse::<SecretStore>().is_err());
+    }
+}
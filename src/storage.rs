@@ -1,4 +1,7 @@
 use crate::credentials::{Credentials, StorageType};
+use crate::option_bytes::ConfigWrite;
+use crate::provisioning::PatchPoint;
+use crate::utils;
 use anyhow::{self, Context};
 use chrono::{DateTime, Utc};
 use s3::{self, serde_types::Object};
@@ -10,22 +13,142 @@ pub struct FirmwareMetadata {
     pub name: String,
     pub version: String,
     pub last_modified: i64,
+    /// Release channel and tags, only filled in by [`Storage::read_tags`]
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl FirmwareMetadata {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Firmware {
     pub name: String,
     pub version: String,
     pub chip: String,
+    /// Other chips the firmware can be flashed onto
+    pub compatible_chips: Vec<String>,
     pub path: PathBuf,
+    pub unique_id: Option<MemoryLocation>,
+    pub provisioning: Vec<PatchPoint>,
+    pub option_bytes: Vec<ConfigWrite>,
+    /// probe-rs target description shipped with the firmware, already registered
+    pub target_description: Option<PathBuf>,
+}
+
+/// Location of a value in the target memory, the address may be given as a
+/// number or as a hex string in the manifest.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLocation {
+    #[serde(deserialize_with = "utils::deserialize_number")]
+    pub address: u64,
+    pub length: usize,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Manifest {
     chip: String,
+    #[serde(default)]
+    compatible_chips: Vec<String>,
+    #[serde(default)]
+    unique_id: Option<MemoryLocation>,
+    #[serde(default)]
+    provisioning: Vec<PatchPoint>,
+    #[serde(default)]
+    option_bytes: Vec<ConfigWrite>,
+    /// File name of a probe-rs target description YAML next to the manifest
+    #[serde(default)]
+    target_description: Option<String>,
+    /// Release channel the firmware was built for, e.g. "nightly"
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl Manifest {
+    /// The channel followed by the other tags.
+    fn tags(&self) -> Vec<String> {
+        self.channel
+            .iter()
+            .chain(
+                self.tags
+                    .iter()
+                    .filter(|t| Some(*t) != self.channel.as_ref()),
+            )
+            .cloned()
+            .collect()
+    }
+}
+
+/// Directory next to the firmware versions holding the channel pointers.
+const CHANNELS_DIR: &str = "channels";
+
+/// Contents of `<name>/channels/<channel>.json`, points a channel to a version.
+#[derive(Deserialize)]
+struct ChannelPointer {
+    version: String,
+}
+
+/// Moves the channel tags to the versions the channel pointers point to.
+fn apply_channel_pointers(versions: &mut [FirmwareMetadata], pointers: &[(String, String)]) {
+    for (channel, version) in pointers {
+        for f in versions.iter_mut() {
+            f.tags.retain(|t| t != channel);
+            if &f.version == version {
+                f.tags.push(channel.clone());
+            }
+        }
+    }
+}
+
+/// Region used for S3 storages that do not set one.
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Object requested by [`Storage::check_access`], it is not expected to exist.
+const ACCESS_CHECK_KEY: &str = ".quick-flash-access-check";
+
+/// Explains an error response of the storage, returns `None` if the response
+/// shows that the bucket can be read (including a missing object).
+fn diagnose_response(status: u16, body: &str) -> Option<String> {
+    let code = body
+        .split_once("<Code>")
+        .and_then(|(_, rest)| rest.split_once("</Code>"))
+        .map_or("", |(code, _)| code);
+    let diagnosis = match code {
+        _ if (200..300).contains(&status) => return None,
+        "NoSuchKey" => return None,
+        "NoSuchBucket" => "Bucket not found, check the bucket name",
+        "InvalidAccessKeyId" => "The access key is not valid, check the access key",
+        "SignatureDoesNotMatch" => {
+            "The secret key does not match the access key, check the secret key"
+        }
+        "RequestTimeTooSkewed" => {
+            "The clock of this computer is too far off from the storage time, check the system time"
+        }
+        "AccessDenied" | "Unauthorized" => {
+            "Access denied, check that the API token is allowed to read the bucket"
+        }
+        "" if status == 404 => return None,
+        _ => {
+            return Some(format!(
+                "The storage refused the request (HTTP {}{}), check the account ID or endpoint and the keys",
+                status,
+                match code.is_empty() {
+                    true => String::new(),
+                    false => format!(" {}", code),
+                }
+            ))
+        }
+    };
+    Some(diagnosis.to_string())
 }
 
 pub struct Storage {
+    name: String,
     bucket: Box<s3::Bucket>,
 }
 
@@ -35,6 +158,16 @@ impl Storage {
             StorageType::R2 => s3::Region::R2 {
                 account_id: creds.storage_account_id.clone(),
             },
+            StorageType::S3 => s3::Region::Custom {
+                region: creds
+                    .storage_region
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_S3_REGION.to_string()),
+                endpoint: creds
+                    .storage_endpoint
+                    .clone()
+                    .context("S3 storage needs an endpoint")?,
+            },
         };
 
         let bucket = s3::Bucket::new(
@@ -48,7 +181,41 @@ impl Storage {
                 expiration: None,
             },
         )?;
-        Ok(Storage { bucket })
+        /* S3-compatible servers often only serve buckets at the endpoint path */
+        let bucket = match creds.storage_type {
+            StorageType::S3 => bucket.with_path_style(),
+            StorageType::R2 => bucket,
+        };
+        Ok(Storage {
+            name: creds.user_storage_name.clone(),
+            bucket,
+        })
+    }
+
+    /// User given name of the storage.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks that the bucket can be read and listed with the credentials,
+    /// the error explains what is most likely wrong with them.
+    pub fn check_access(&self) -> anyhow::Result<()> {
+        let response = self.bucket.get_object(ACCESS_CHECK_KEY).map_err(|e| {
+            anyhow::anyhow!(
+                "Could not reach the storage ({}), check the account ID or endpoint and the network connection",
+                e
+            )
+        })?;
+        if let Some(diagnosis) = diagnose_response(
+            response.status_code(),
+            response.as_str().unwrap_or_default(),
+        ) {
+            anyhow::bail!(diagnosis);
+        }
+        self.bucket
+            .list("".to_string(), Some("/".to_string()))
+            .context("Failed to list the bucket, check that the API token is allowed to list it")?;
+        Ok(())
     }
 
     pub fn is_available(&self) -> anyhow::Result<()> {
@@ -93,6 +260,7 @@ impl Storage {
                         .ok()?
                         .with_timezone(&Utc)
                         .timestamp(),
+                    tags: Vec::new(),
                 })
             } else {
                 None
@@ -128,6 +296,98 @@ impl Storage {
         self.list_object_metadata(firmware_name.clone())
     }
 
+    /// Fills in the tags of `versions` of `firmware_name` from their manifests
+    /// and the pointers in `<name>/channels/`. A channel pointer overrides the
+    /// channel given in the manifests.
+    pub fn read_tags(
+        &self,
+        firmware_name: &str,
+        versions: &mut [FirmwareMetadata],
+        cache_base: &Path,
+    ) -> anyhow::Result<()> {
+        for f in versions.iter_mut() {
+            let cache_dir = cache_base.join(&f.name).join(&f.version);
+            f.tags = self
+                .read_manifest(&f.name, &f.version, &cache_dir)
+                .with_context(|| format!("Failed to read manifest of version \"{}\"", f.version))?
+                .tags();
+        }
+        let pointers = self
+            .read_channel_pointers(firmware_name)
+            .context("Failed to read channel pointers")?;
+        apply_channel_pointers(versions, &pointers);
+        Ok(())
+    }
+
+    fn read_channel_pointers(&self, firmware_name: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let prefix = format!("{}/{}/", firmware_name, CHANNELS_DIR);
+        let keys = self
+            .bucket
+            .list(prefix.clone(), None)?
+            .iter()
+            .flat_map(|r| r.contents.iter().map(|o| o.key.clone()))
+            .collect::<Vec<String>>();
+
+        keys.iter()
+            .filter_map(|k| k.strip_prefix(&prefix)?.strip_suffix(".json"))
+            .map(|channel| {
+                let object = self
+                    .bucket
+                    .get_object(format!("{}{}.json", prefix, channel))?;
+                if object.status_code() != 200 {
+                    anyhow::bail!(
+                        "Failed to download channel \"{}\" (HTTP {})",
+                        channel,
+                        object.status_code()
+                    );
+                }
+                let pointer: ChannelPointer = serde_json::from_slice(object.bytes())
+                    .with_context(|| format!("Invalid channel pointer \"{}\"", channel))?;
+                Ok((channel.to_owned(), pointer.version))
+            })
+            .collect()
+    }
+
+    /// Reads the manifest of a version, downloading it into `cache_dir` first
+    /// if it is not cached yet.
+    fn read_manifest(
+        &self,
+        name: &str,
+        version: &str,
+        cache_dir: &Path,
+    ) -> anyhow::Result<Manifest> {
+        let cache_manifest = cache_dir.join("manifest.json");
+        if !cache_manifest.exists() {
+            let manifest = self
+                .bucket
+                .get_object(format!("{}/{}/manifest.json", name, version))?;
+            if manifest.status_code() != 200 {
+                anyhow::bail!(
+                    "Failed to download manifest (HTTP {})",
+                    manifest.status_code()
+                );
+            }
+            std::fs::create_dir_all(cache_dir)?;
+            std::fs::write(&cache_manifest, manifest.bytes())?;
+        }
+        let manifest = std::fs::read_to_string(cache_manifest)?;
+        Ok(serde_json::from_str(&manifest)?)
+    }
+
+    /// Resolves a version spec such as `latest` or `^1.4` to a concrete
+    /// version of `firmware_name`, see [`crate::versions::resolve_version`].
+    pub fn resolve_firmware_version(
+        &self,
+        firmware_name: &str,
+        spec: &str,
+    ) -> anyhow::Result<String> {
+        let versions = self
+            .list_firmware_versions(firmware_name)
+            .context("Failed to fetch firmware versions from the Bucket")?;
+        crate::versions::resolve_version(spec, &versions)
+            .with_context(|| format!("Firmware \"{}\" has no matching version", firmware_name))
+    }
+
     pub fn download_firmware(
         &self,
         name: &str,
@@ -136,28 +396,154 @@ impl Storage {
     ) -> anyhow::Result<Firmware> {
         let cache_base = cache_base.to_path_buf().join(name).join(version);
         let cache_firmware = cache_base.join("firmware.elf");
-        let cache_manifest = cache_base.join("manifest.json");
 
-        if !cache_base.exists() {
+        if !cache_firmware.exists() {
             std::fs::create_dir_all(&cache_base)?;
-            let bucket_base = format!("{}/{}", name, version);
-            let bucket_firmware = format!("{}/firmware.elf", bucket_base);
-            let bucket_manifest = format!("{}/manifest.json", bucket_base);
+            let bucket_firmware = format!("{}/{}/firmware.elf", name, version);
 
             eprintln!("Downloading firmware to {}...", cache_base.display());
             let firmware = self.bucket.get_object(&bucket_firmware)?;
             std::fs::write(&cache_firmware, firmware.bytes())?;
-            let manifest = self.bucket.get_object(&bucket_manifest)?;
-            std::fs::write(&cache_manifest, manifest.bytes())?;
         }
 
-        let manifest: Manifest = serde_json::from_str(&std::fs::read_to_string(cache_manifest)?)?;
+        let manifest = self.read_manifest(name, version, &cache_base)?;
+
+        let target_description = match manifest.target_description {
+            Some(file) => {
+                if file.contains(['/', '\\']) || file.starts_with('.') {
+                    anyhow::bail!("Invalid target description file name \"{}\"", file);
+                }
+                let cache_target = cache_base.join(&file);
+                if !cache_target.exists() {
+                    let target = self
+                        .bucket
+                        .get_object(format!("{}/{}/{}", name, version, file))
+                        .context("Failed to download target description")?;
+                    if target.status_code() != 200 {
+                        anyhow::bail!(
+                            "Failed to download target description \"{}\" (HTTP {})",
+                            file,
+                            target.status_code()
+                        );
+                    }
+                    std::fs::write(&cache_target, target.bytes())?;
+                }
+                crate::register_target_description(&cache_target)?;
+                Some(cache_target)
+            }
+            None => None,
+        };
 
         Ok(Firmware {
             name: name.to_owned(),
             version: version.to_owned(),
             chip: manifest.chip,
+            compatible_chips: manifest.compatible_chips,
             path: cache_firmware,
+            unique_id: manifest.unique_id,
+            provisioning: manifest.provisioning,
+            option_bytes: manifest.option_bytes,
+            target_description,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_parsing() {
+        let manifest: Manifest = serde_json::from_str(r#"{"chip": "nRF52840_xxAA"}"#).unwrap();
+        assert_eq!(manifest.chip, "nRF52840_xxAA");
+        assert_eq!(manifest.unique_id, None);
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"chip": "STM32G071RBTx", "unique_id": {"address": "0x1FFF7590", "length": 12}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.unique_id,
+            Some(MemoryLocation {
+                address: 0x1FFF_7590,
+                length: 12
+            })
+        );
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"chip": "nRF52840_xxAA", "unique_id": {"address": 268435552, "length": 8}}"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.unique_id.unwrap().address, 0x1000_0060);
+
+        assert!(serde_json::from_str::<Manifest>(
+            r#"{"chip": "nRF52840_xxAA", "unique_id": {"address": "0xZZ", "length": 8}}"#
+        )
+        .is_err());
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"chip": "nRF52840_xxAA", "provisioning": [
+                {"name": "serial", "symbol": "SERIAL_NUMBER", "size": 4, "encoding": "le",
+                 "source": {"type": "counter", "start": 1000}},
+                {"name": "mac", "address": "0x000FF000", "size": 6, "encoding": "hex",
+                 "source": {"type": "csv", "column": "mac"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.provisioning.len(), 2);
+        assert_eq!(manifest.provisioning[1].address, Some(0x000F_F000));
+    }
+
+    #[test]
+    fn test_diagnose_response() {
+        let error = |code: &str| {
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{}</Code><Message>...</Message></Error>",
+                code
+            )
+        };
+        assert_eq!(diagnose_response(200, ""), None);
+        assert_eq!(diagnose_response(404, &error("NoSuchKey")), None);
+        assert!(diagnose_response(404, &error("NoSuchBucket"))
+            .unwrap()
+            .contains("bucket name"));
+        assert!(diagnose_response(403, &error("SignatureDoesNotMatch"))
+            .unwrap()
+            .contains("secret key"));
+        assert!(diagnose_response(403, &error("RequestTimeTooSkewed"))
+            .unwrap()
+            .contains("system time"));
+        assert_eq!(
+            diagnose_response(400, &error("InvalidArgument")).unwrap(),
+            "The storage refused the request (HTTP 400 InvalidArgument), check the account ID or endpoint and the keys"
+        );
+    }
+
+    #[test]
+    fn test_channel_tags() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"chip": "nRF52840_xxAA", "channel": "beta", "tags": ["rc", "beta"]}"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.tags(), vec!["beta", "rc"]);
+
+        let mut versions = [("1.0.0", vec!["stable"]), ("1.1.0", vec!["beta", "stable"])].map(
+            |(version, tags)| FirmwareMetadata {
+                name: "blinky".to_string(),
+                version: version.to_string(),
+                last_modified: 0,
+                tags: tags.into_iter().map(str::to_owned).collect(),
+            },
+        );
+        apply_channel_pointers(
+            &mut versions,
+            &[
+                ("stable".to_string(), "1.0.0".to_string()),
+                ("nightly".to_string(), "1.1.0".to_string()),
+            ],
+        );
+        assert_eq!(versions[0].tags, vec!["stable"]);
+        assert_eq!(versions[1].tags, vec!["beta", "nightly"]);
+        assert!(versions[1].has_tag("nightly"));
+    }
+}
@@ -1,4 +1,3 @@
-
 pub fn read_line() -> Result<String, std::io::Error> {
     let mut buffer = String::new();
     std::io::stdin().read_line(&mut buffer)?;
@@ -12,3 +11,52 @@ pub fn read_line() -> Result<String, std::io::Error> {
         Ok(line)
     }
 }
+
+/// Writes a file readable only by its owner, also restricting the
+/// permissions of an already existing file.
+pub fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+/// Parses a decimal or a `0x` prefixed hexadecimal number.
+pub fn parse_number(s: &str) -> Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Deserializes a number given either as an integer or as a string accepted
+/// by [`parse_number`], used for addresses in manifest files.
+pub fn deserialize_number<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u64, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Int(u64),
+        Str(String),
+    }
+
+    match <Number as serde::Deserialize>::deserialize(deserializer)? {
+        Number::Int(n) => Ok(n),
+        Number::Str(s) => parse_number(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Like [`deserialize_number`], for optional fields marked `#[serde(default)]`.
+pub fn deserialize_optional_number<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    deserialize_number(deserializer).map(Some)
+}
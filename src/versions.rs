@@ -0,0 +1,311 @@
+use crate::storage::FirmwareMetadata;
+use regex::Regex;
+use semver::{Version, VersionReq};
+use std::str::FromStr;
+
+/// Picks the newest uploaded version.
+pub const LATEST: &str = "latest";
+/// Picks the highest version that is a valid semantic version, pre-releases
+/// are only picked when named explicitly or by a requirement.
+pub const LATEST_SEMVER: &str = "latest-semver";
+
+/// Git hash prefixes shorter than this are not matched, to not confuse them
+/// with plain version numbers.
+const MIN_HASH_PREFIX: usize = 4;
+
+/// Parses a version name as a semantic version, allowing a leading 'v'.
+fn parse_semver(version: &str) -> Option<Version> {
+    let version = version
+        .strip_prefix(['v', 'V'])
+        .filter(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap_or(version);
+    Version::parse(version).ok()
+}
+
+fn highest_semver(
+    versions: &[FirmwareMetadata],
+    filter: impl Fn(&Version) -> bool,
+) -> Option<&FirmwareMetadata> {
+    versions
+        .iter()
+        .filter_map(|f| parse_semver(&f.version).map(|v| (v, f)))
+        .filter(|(v, _)| filter(v))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, f)| f)
+}
+
+fn is_hash_prefix(spec: &str) -> bool {
+    spec.len() >= MIN_HASH_PREFIX && spec.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether one of the parts of `version` is a git hash starting with `prefix`,
+/// both plain hashes and `git describe` output like `v1.2.0-3-gabc1234` match.
+fn matches_hash(version: &str, prefix: &str) -> bool {
+    version.split(['-', '+', '.', '_']).any(|part| {
+        let part = part.to_ascii_lowercase();
+        let prefix = prefix.to_ascii_lowercase();
+        part.starts_with(&prefix)
+            || part
+                .strip_prefix('g')
+                .is_some_and(|p| p.starts_with(&prefix))
+    })
+}
+
+/// Resolves a version `spec` given by the user to one of the concrete `versions`.
+///
+/// The spec can be an exact version name, `latest` for the most recently
+/// uploaded version, `latest-semver` for the highest semantic version, a
+/// semver requirement such as `^1.4` or `~2.0.3`, or a git hash prefix.
+/// Exact names always take precedence, and specs that parse as a semver
+/// requirement are never taken as a hash prefix.
+pub fn resolve_version(spec: &str, versions: &[FirmwareMetadata]) -> anyhow::Result<String> {
+    if let Some(f) = versions.iter().find(|f| f.version == spec) {
+        return Ok(f.version.clone());
+    }
+
+    let resolved = match spec {
+        LATEST => versions.iter().max_by_key(|f| f.last_modified),
+        LATEST_SEMVER => highest_semver(versions, |v| v.pre.is_empty()),
+        _ => {
+            let requirement = spec.strip_prefix(['v', 'V']).unwrap_or(spec);
+            match VersionReq::parse(requirement) {
+                /* plain numbers like "2024" are requirements, never hash prefixes */
+                Ok(req) => highest_semver(versions, |v| req.matches(v)),
+                Err(_) if is_hash_prefix(spec) => {
+                    let hash_matches = versions
+                        .iter()
+                        .filter(|f| matches_hash(&f.version, spec))
+                        .collect::<Vec<_>>();
+                    match &hash_matches[..] {
+                        [f] => Some(*f),
+                        [] => None,
+                        _ => anyhow::bail!(
+                            "Version \"{}\" is ambiguous, it matches {}",
+                            spec,
+                            hash_matches
+                                .iter()
+                                .map(|f| f.version.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    }
+                }
+                Err(_) => None,
+            }
+        }
+    };
+
+    resolved
+        .map(|f| f.version.clone())
+        .ok_or_else(|| anyhow::anyhow!("No version matches \"{}\"", spec))
+}
+
+/// Order of firmware listings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Oldest upload first
+    Date,
+    /// Lowest semantic version first, versions that are not semver come first
+    Semver,
+    /// Alphabetical order of the listed names or versions
+    Name,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "date" => Ok(SortKey::Date),
+            "semver" => Ok(SortKey::Semver),
+            "name" => Ok(SortKey::Name),
+            _ => anyhow::bail!(
+                "Invalid sort order \"{}\", expected date, semver or name",
+                s
+            ),
+        }
+    }
+}
+
+/// Which field of [`FirmwareMetadata`] a listing shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListField {
+    Name,
+    Version,
+}
+
+impl ListField {
+    fn of(self, f: &FirmwareMetadata) -> &str {
+        match self {
+            ListField::Name => &f.name,
+            ListField::Version => &f.version,
+        }
+    }
+}
+
+/// Sorting and filtering of firmware name and version listings.
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    pub sort: Option<SortKey>,
+    pub reverse: bool,
+    /// Only keep this many entries after sorting
+    pub limit: Option<usize>,
+    /// Matched against the listed field
+    pub filter: Option<Regex>,
+    /// Only keep entries modified at or after this timestamp
+    pub since: Option<i64>,
+}
+
+impl ListQuery {
+    /// Filters, sorts and limits a listing of `field`, entries are kept in
+    /// the order of the bucket when no sort key is given.
+    pub fn apply(
+        &self,
+        mut items: Vec<FirmwareMetadata>,
+        field: ListField,
+    ) -> Vec<FirmwareMetadata> {
+        items.retain(|f| {
+            self.filter.as_ref().is_none_or(|r| r.is_match(field.of(f)))
+                && self.since.is_none_or(|t| f.last_modified >= t)
+        });
+        match self.sort {
+            Some(SortKey::Date) => items.sort_by_key(|f| f.last_modified),
+            Some(SortKey::Semver) => items.sort_by_cached_key(|f| {
+                let value = field.of(f);
+                (parse_semver(value), value.to_owned())
+            }),
+            Some(SortKey::Name) => items.sort_by(|a, b| field.of(a).cmp(field.of(b))),
+            None => {}
+        }
+        if self.reverse {
+            items.reverse();
+        }
+        if let Some(limit) = self.limit {
+            items.truncate(limit);
+        }
+        items
+    }
+}
+
+/// Turns a glob with `*` and `?` wildcards into a regex matching whole names.
+pub fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_version() {
+        let versions = [
+            ("v1.4.2", 100),
+            ("v1.10.0", 200),
+            ("2.0.3", 150),
+            ("2.0.7-rc.1", 400),
+            ("nightly-3f2a9c1", 500),
+            ("v1.3.0-2-gdeadbee", 50),
+            ("fast", 10),
+            ("2024.10.01", 20),
+        ]
+        .map(|(version, last_modified)| FirmwareMetadata {
+            name: "blinky".to_string(),
+            version: version.to_string(),
+            last_modified,
+            tags: Vec::new(),
+        });
+        let resolve = |spec| resolve_version(spec, &versions).ok();
+
+        assert_eq!(resolve("fast").as_deref(), Some("fast"));
+        assert_eq!(resolve("latest").as_deref(), Some("nightly-3f2a9c1"));
+        assert_eq!(resolve("latest-semver").as_deref(), Some("2.0.3"));
+        assert_eq!(resolve("^1.4").as_deref(), Some("v1.10.0"));
+        assert_eq!(resolve("~1.4").as_deref(), Some("v1.4.2"));
+        assert_eq!(resolve("~2.0.3").as_deref(), Some("2.0.3"));
+        assert_eq!(resolve("v2").as_deref(), Some("2.0.3"));
+        assert_eq!(resolve("3f2a").as_deref(), Some("nightly-3f2a9c1"));
+        assert_eq!(resolve("DEADBEE").as_deref(), Some("v1.3.0-2-gdeadbee"));
+        assert_eq!(resolve(">=2.0.7-rc.1").as_deref(), Some("2.0.7-rc.1"));
+        assert_eq!(resolve("^3"), None);
+        assert_eq!(resolve("2024"), None);
+        assert_eq!(resolve("slow"), None);
+    }
+
+    #[test]
+    fn test_list_query() {
+        let versions = [
+            ("v2.1.0", 300),
+            ("v10.0.0", 100),
+            ("v2.0.0", 200),
+            ("fast", 400),
+        ]
+        .map(|(version, last_modified)| FirmwareMetadata {
+            name: "blinky".to_string(),
+            version: version.to_string(),
+            last_modified,
+            tags: Vec::new(),
+        })
+        .to_vec();
+        let listed = |query: ListQuery| {
+            query
+                .apply(versions.clone(), ListField::Version)
+                .into_iter()
+                .map(|f| f.version)
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            listed(ListQuery {
+                sort: Some(SortKey::Semver),
+                ..Default::default()
+            }),
+            ["fast", "v2.0.0", "v2.1.0", "v10.0.0"]
+        );
+        assert_eq!(
+            listed(ListQuery {
+                sort: Some(SortKey::Date),
+                reverse: true,
+                limit: Some(2),
+                ..Default::default()
+            }),
+            ["fast", "v2.1.0"]
+        );
+        assert_eq!(
+            listed(ListQuery {
+                sort: Some(SortKey::Name),
+                filter: Some(glob_to_regex("v2.*").unwrap()),
+                since: Some(250),
+                ..Default::default()
+            }),
+            ["v2.1.0"]
+        );
+
+        /* names are sorted by themselves, not by the version of their newest upload */
+        let names = [("sensor-b", "v1.0.0"), ("sensor-a", "v2.0.0")].map(|(name, version)| {
+            FirmwareMetadata {
+                name: name.to_string(),
+                version: version.to_string(),
+                last_modified: 0,
+                tags: Vec::new(),
+            }
+        });
+        let sorted = ListQuery {
+            sort: Some(SortKey::Semver),
+            ..Default::default()
+        }
+        .apply(names.to_vec(), ListField::Name);
+        assert_eq!(sorted[0].name, "sensor-a");
+
+        assert!(!glob_to_regex("v2.?").unwrap().is_match("v2.10"));
+        assert!("size".parse::<SortKey>().is_err());
+    }
+}